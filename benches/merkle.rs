@@ -0,0 +1,39 @@
+//! Building a Merkle tree of 10k leaves, and proving and verifying one of them.
+
+use bitcoin::crypto::hash::{Hashable, H256};
+use bitcoin::crypto::merkle::{verify, MerkleTree};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const LEAVES: usize = 10_000;
+
+fn leaves() -> Vec<H256> {
+    (0..LEAVES)
+        .map(|i| {
+            let mut leaf = [0u8; 32];
+            leaf[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            H256::from(leaf)
+        })
+        .collect()
+}
+
+fn merkle(c: &mut Criterion) {
+    let data = leaves();
+    let tree = MerkleTree::new(&data);
+    let index = LEAVES / 3;
+    let proof = tree.proof(index);
+    let root = tree.root();
+    let datum = data[index].hash();
+
+    c.bench_function("merkle new 10k", |b| {
+        b.iter(|| MerkleTree::new(black_box(&data)))
+    });
+    c.bench_function("merkle proof 10k", |b| {
+        b.iter(|| tree.proof(black_box(index)))
+    });
+    c.bench_function("merkle verify 10k", |b| {
+        b.iter(|| verify(&root, &datum, black_box(&proof), index, LEAVES))
+    });
+}
+
+criterion_group!(benches, merkle);
+criterion_main!(benches);
@@ -0,0 +1,89 @@
+//! Verifying the signatures of a block one transaction at a time with `transaction::verify`,
+//! against the parallel `Verifier` with an empty and a warm cache.
+
+use bitcoin::block::{Block, Content, Header};
+use bitcoin::crypto::hash::H256;
+use bitcoin::crypto::merkle::MerkleTree;
+use bitcoin::script::Context;
+use bitcoin::transaction::{self, generate_random_signed_transaction};
+use bitcoin::verifier::{Verifier, CACHE_SIZE};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ring::signature::KeyPair;
+
+const CONTEXT: Context = Context { height: 1, time: 0 };
+
+fn block(
+    size: usize,
+) -> (
+    Block,
+    Vec<(transaction::Transaction, ring::signature::Ed25519KeyPair)>,
+) {
+    let generated: Vec<_> = (0..size)
+        .map(|_| generate_random_signed_transaction())
+        .collect();
+    let data: Vec<_> = generated
+        .iter()
+        .map(|(_, signed, _)| signed.clone())
+        .collect();
+    let header = Header {
+        parent: H256::from([0; 32]),
+        nonce: 0,
+        difficulty: H256::from([0xff; 32]),
+        timestamp: 0,
+        merkle_root: MerkleTree::new(&data).root(),
+    };
+    let block = Block {
+        header,
+        content: Content { data },
+    };
+    let raw = generated
+        .into_iter()
+        .map(|(raw, _, key)| (raw, key))
+        .collect();
+    (block, raw)
+}
+
+fn verify(c: &mut Criterion) {
+    let threads = std::thread::available_parallelism().map_or(1, |x| x.get());
+    let mut group = c.benchmark_group("verify block");
+    for size in [100, 1000].iter() {
+        let (block, raw) = block(*size);
+        let signed: Vec<_> = raw
+            .iter()
+            .map(|(t, key)| (t, key.public_key(), transaction::sign(t, key)))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("transaction::verify", size),
+            &signed,
+            |b, signed| {
+                b.iter(|| {
+                    signed
+                        .iter()
+                        .all(|(t, pub_key, signature)| transaction::verify(t, pub_key, signature))
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("Verifier, empty cache", size),
+            &block,
+            |b, block| {
+                b.iter_batched(
+                    || Verifier::new(CACHE_SIZE, threads),
+                    |verifier| verifier.validate_block(block, &CONTEXT).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+        let warm = Verifier::new(CACHE_SIZE, threads);
+        warm.validate_block(&block, &CONTEXT).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("Verifier, warm cache", size),
+            &block,
+            |b, block| b.iter(|| warm.validate_block(block, &CONTEXT).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, verify);
+criterion_main!(benches);
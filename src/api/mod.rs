@@ -1,28 +1,71 @@
 use serde::Serialize;
+use crate::blockchain::Blockchain;
+use crate::crypto::address::Address;
+use crate::crypto::hash::{Hashable, H256};
+use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
-use crate::network::server::Handle as NetworkServerHandle;
-use crate::network::message::Message;
+use crate::network::server::{Handle as NetworkServerHandle, DEFAULT_BAN_DURATION};
+use crate::script::Context;
+use crate::wallet::Wallet;
 
-use log::info;
+use crossbeam::channel::Sender;
+use log::{error, info};
 use std::collections::HashMap;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+mod views;
+
+use views::{BlockView, ForkView, TipView, TransactionStatus};
+
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    node: NodeState,
+}
+
+/// The state of the node shared with the API
+#[derive(Clone)]
+pub struct NodeState {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    /// The wallet of the node, if it was given one
+    pub wallet: Option<Arc<Mutex<Wallet>>>,
+    /// Asks the node to shut down
+    pub shutdown: Sender<()>,
+    /// Cleared when the node is shutting down
+    pub running: Arc<AtomicBool>,
 }
 
+/// How often the request loop checks whether the node is shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// The largest request body read
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+/// A successful response carrying data rather than a message
+#[derive(Serialize)]
+struct ApiData<T: Serialize> {
+    success: bool,
+    data: T,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -36,23 +79,69 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $data:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiData {
+            success: true,
+            data: $data,
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+/// Parse a query parameter, or describe why it cannot be.
+fn param<T>(params: &HashMap<String, String>, name: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = params
+        .get(name)
+        .ok_or_else(|| format!("missing {}", name))?;
+    value
+        .parse::<T>()
+        .map_err(|e| format!("error parsing {}: {}", name, e))
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
-    ) {
+        node: &NodeState,
+    ) -> thread::JoinHandle<()> {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            node: node.clone(),
         };
-        thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
+        let running = Arc::clone(&node.running);
+        let thread_handle = thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                let req = match server.handle.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                    Ok(Some(req)) => req,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("API server error: {}", e);
+                        break;
+                    }
+                };
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let NodeState {
+                    blockchain,
+                    mempool,
+                    wallet,
+                    shutdown,
+                    ..
+                } = server.node.clone();
                 thread::spawn(move || {
+                    let mut req = req;
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
                     let url = match base_url.join(req.url()) {
@@ -87,9 +176,298 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
-                        "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
-                            respond_result!(req, true, "ok");
+                        "/node/shutdown" => {
+                            respond_result!(req, true, "shutting down");
+                            shutdown.send(()).unwrap();
+                        }
+                        "/network/ping" => match network.ping() {
+                            Ok(delivery) => {
+                                let message = format!(
+                                    "pinged {} peers, {} failed",
+                                    delivery.delivered,
+                                    delivery.failed.len()
+                                );
+                                respond_result!(req, true, message);
+                            }
+                            Err(e) => respond_result!(req, false, e),
+                        },
+                        "/network/peers" => match network.peers() {
+                            Ok(peers) => respond_json!(req, peers),
+                            Err(e) => respond_result!(req, false, e),
+                        },
+                        "/network/connect" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let addr = match param::<SocketAddr>(&params, "addr") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match network.connect(addr) {
+                                Ok(_) => {
+                                    respond_result!(req, true, format!("connected to {}", addr))
+                                }
+                                Err(e) => respond_result!(
+                                    req,
+                                    false,
+                                    format!("error connecting to {}: {}", addr, e)
+                                ),
+                            }
+                        }
+                        "/network/disconnect" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let addr = match param::<SocketAddr>(&params, "addr") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match network.disconnect(addr) {
+                                Ok(true) => {
+                                    respond_result!(req, true, format!("disconnected {}", addr))
+                                }
+                                Ok(false) => respond_result!(
+                                    req,
+                                    false,
+                                    format!("not connected to {}", addr)
+                                ),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/ban" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            // accept a peer address as listed by /network/peers, or a bare IP
+                            let ip = match param::<IpAddr>(&params, "addr") {
+                                Ok(v) => v,
+                                Err(e) => match param::<SocketAddr>(&params, "addr") {
+                                    Ok(v) => v.ip(),
+                                    Err(_) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                },
+                            };
+                            let duration = match params.get("secs") {
+                                None => DEFAULT_BAN_DURATION,
+                                Some(_) => match param::<u64>(&params, "secs") {
+                                    Ok(v) => Duration::from_secs(v),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                },
+                            };
+                            match network.ban(ip, duration) {
+                                Ok(count) => respond_result!(
+                                    req,
+                                    true,
+                                    format!("banned {}, disconnected {} peers", ip, count)
+                                ),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/blockchain/tip" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = TipView {
+                                hash: blockchain.tip().to_string(),
+                                height: blockchain.chainLength - 1,
+                            };
+                            respond_json!(req, tip);
+                        }
+                        "/blockchain/longest-chain" => {
+                            let mut chain =
+                                blockchain.lock().unwrap().all_blocks_in_longest_chain();
+                            chain.reverse();
+                            let chain: Vec<String> = chain.iter().map(|x| x.to_string()).collect();
+                            respond_json!(req, chain);
+                        }
+                        "/blockchain/forks" => {
+                            let forks = blockchain.lock().unwrap().forks();
+                            let forks: Vec<ForkView> = forks.iter().map(ForkView::from).collect();
+                            respond_json!(req, forks);
+                        }
+                        path if path.starts_with("/block/height/") => {
+                            let height = match path["/block/height/".len()..].parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing height: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.hash_at_height(height) {
+                                Some(hash) => {
+                                    let block =
+                                        BlockView::new(&blockchain.blockMap[&hash], &blockchain);
+                                    respond_json!(req, block);
+                                }
+                                None => respond_result!(
+                                    req,
+                                    false,
+                                    format!("no block at height {}", height)
+                                ),
+                            }
+                        }
+                        path if path.starts_with("/block/") => {
+                            let hash = match path["/block/".len()..].parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.blockMap.get(&hash) {
+                                Some(block) => {
+                                    let block = BlockView::new(block, &blockchain);
+                                    respond_json!(req, block);
+                                }
+                                None => {
+                                    respond_result!(req, false, format!("unknown block {}", hash))
+                                }
+                            }
+                        }
+                        "/tx" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "transactions must be POSTed");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req
+                                .as_reader()
+                                .take(MAX_BODY_SIZE)
+                                .read_to_string(&mut body)
+                            {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let transaction = match views::parse_transaction(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            if let Err(e) = transaction.validate(&Context::next_block(&blockchain))
+                            {
+                                respond_result!(req, false, format!("invalid transaction: {}", e));
+                                return;
+                            }
+                            let hash = transaction.hash();
+                            let mut mempool = mempool.lock().unwrap();
+                            if !mempool.contains(&hash) {
+                                let nonce = mempool.next_nonce(
+                                    &transaction.input,
+                                    blockchain.next_nonce(&transaction.input),
+                                );
+                                if transaction.nonce != nonce {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!(
+                                            "invalid transaction: nonce {} instead of {}",
+                                            transaction.nonce, nonce
+                                        )
+                                    );
+                                    return;
+                                }
+                                mempool.insert(transaction);
+                                network.announce_transactions(vec![hash]);
+                            }
+                            respond_result!(req, true, hash);
+                        }
+                        path if path.starts_with("/tx/") => {
+                            let hash = match path["/tx/".len()..].parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            if mempool.lock().unwrap().contains(&hash) {
+                                respond_json!(req, TransactionStatus::Pending);
+                                return;
+                            }
+                            let blockchain = blockchain.lock().unwrap();
+                            let status = match blockchain.find_transaction(&hash) {
+                                Some((block, height)) => TransactionStatus::Confirmed {
+                                    block: block.to_string(),
+                                    confirmations: blockchain.chainLength - height,
+                                },
+                                None => TransactionStatus::Unknown,
+                            };
+                            respond_json!(req, status);
+                        }
+                        path if path.starts_with("/wallet/") && wallet.is_none() => {
+                            respond_result!(req, false, "no wallet is open");
+                        }
+                        "/wallet/new-address" => {
+                            let mut wallet = wallet.as_ref().unwrap().lock().unwrap();
+                            match wallet.new_address() {
+                                Ok(address) => respond_result!(req, true, Address::new(address)),
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error saving key: {}", e))
+                                }
+                            }
+                        }
+                        "/wallet/balance" => {
+                            let wallet = wallet.as_ref().unwrap().lock().unwrap();
+                            let blockchain = blockchain.lock().unwrap();
+                            let balance = wallet.balance(&blockchain, &mempool.lock().unwrap());
+                            respond_json!(req, balance);
+                        }
+                        "/wallet/send" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let to = match param::<String>(&params, "to")
+                                .and_then(|x| Address::parse_for_network(&x))
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let amount = match param::<f32>(&params, "amount") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let wallet = wallet.as_ref().unwrap().lock().unwrap();
+                            let blockchain = blockchain.lock().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let transaction =
+                                match wallet.transfer(to, amount, &blockchain, &mempool) {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error signing transfer: {}", e)
+                                        );
+                                        return;
+                                    }
+                                };
+                            let hash = transaction.hash();
+                            mempool.insert(transaction);
+                            network.announce_transactions(vec![hash]);
+                            respond_result!(req, true, hash);
                         }
                         _ => {
                             let content_type =
@@ -108,7 +486,111 @@ impl Server {
                     }
                 });
             }
+            info!("API server stopped");
         });
         info!("API server listening at {}", &addr);
+        thread_handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner;
+    use crate::network::peer;
+    use crate::network::server::memory;
+    use crossbeam::channel;
+    use std::io::Write;
+
+    /// A free local address, for servers that cannot report the port they bound
+    fn free_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Send a GET request to the API and decode the JSON response
+    fn get(api: SocketAddr, path: &str) -> serde_json::Value {
+        let mut stream = std::net::TcpStream::connect(api).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, api).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        serde_json::from_str(body).unwrap()
+    }
+
+    fn peer_count(api: SocketAddr) -> usize {
+        get(api, "/network/peers")["data"].as_array().unwrap().len()
+    }
+
+    #[test]
+    fn network_endpoints() {
+        // an in-memory network server with two peers, polled in the background
+        let (mut network_ctx, network) = memory::new();
+        let first: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:6000".parse().unwrap();
+        let mut queues = vec![];
+        for addr in [first, second].iter() {
+            let (peer, queue) = peer::new_in_memory(*addr, peer::Direction::Outgoing);
+            network_ctx.add_peer(peer);
+            queues.push(queue);
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let network_thread = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    network_ctx.poll();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (_, miner) = miner::new(&network, &blockchain, &mempool);
+        let (shutdown, _) = channel::unbounded();
+        let api = free_addr();
+        let node = NodeState {
+            blockchain,
+            mempool,
+            wallet: None,
+            shutdown,
+            running: Arc::clone(&running),
+        };
+        let api_thread = Server::start(api, &miner, &network, &node);
+
+        let peers = get(api, "/network/peers");
+        assert_eq!(peers["data"].as_array().unwrap().len(), 2);
+        assert_eq!(peers["data"][0]["addr"], first.to_string());
+
+        // nothing listens at a free address
+        let response = get(api, &format!("/network/connect?addr={}", free_addr()));
+        assert_eq!(response["success"], false);
+        assert!(response["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("error connecting"));
+        assert_eq!(get(api, "/network/connect?addr=nowhere")["success"], false);
+
+        let disconnect = format!("/network/disconnect?addr={}", first);
+        assert_eq!(get(api, &disconnect)["success"], true);
+        assert_eq!(get(api, &disconnect)["success"], false);
+        assert_eq!(peer_count(api), 1);
+
+        let response = get(api, "/network/ban?addr=127.0.0.2&secs=60");
+        assert_eq!(response["success"], true);
+        assert_eq!(
+            response["message"],
+            "banned 127.0.0.2, disconnected 1 peers"
+        );
+        assert_eq!(get(api, "/network/ban?addr=nowhere")["success"], false);
+        assert_eq!(
+            get(api, "/network/ban?addr=127.0.0.1&secs=x")["success"],
+            false
+        );
+        assert_eq!(peer_count(api), 0);
+
+        running.store(false, Ordering::SeqCst);
+        api_thread.join().unwrap();
+        network_thread.join().unwrap();
     }
 }
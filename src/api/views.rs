@@ -0,0 +1,260 @@
+//! JSON views of chain data, with hashes and addresses as hex strings.
+
+use crate::block::{Block, Header};
+use crate::blockchain::{Blockchain, Fork};
+use crate::crypto::address::Address;
+use crate::crypto::hash::{Hashable, H160, H256};
+use crate::multisig::Multisig;
+use crate::transaction::SignedTransaction;
+use serde::{Deserialize, Serialize};
+
+fn address(address: &H160) -> String {
+    Address::new(*address).to_string()
+}
+
+fn signature(signature: &[H256; 2]) -> String {
+    format!("{}{}", signature[0], signature[1])
+}
+
+fn parse_signature(signature: &str) -> Result<[H256; 2], String> {
+    if signature.len() != 128 {
+        return Err("signature must be 128 hex digits".to_string());
+    }
+    let (first, second) = signature.split_at(64);
+    Ok([first.parse()?, second.parse()?])
+}
+
+#[derive(Serialize)]
+pub struct HeaderView {
+    pub hash: String,
+    pub parent: String,
+    pub nonce: u32,
+    pub difficulty: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub merkle_root: String,
+}
+
+impl From<&Header> for HeaderView {
+    fn from(header: &Header) -> Self {
+        HeaderView {
+            hash: header.hash().to_string(),
+            parent: header.parent.to_string(),
+            nonce: header.nonce,
+            difficulty: header.difficulty.to_string(),
+            timestamp: header.timestamp as u64,
+            merkle_root: header.merkle_root.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TransactionView {
+    pub hash: String,
+    pub input: String,
+    pub output: String,
+    pub amount: f32,
+    pub lock_time: u64,
+    pub nonce: u64,
+    pub pub_key: String,
+    pub signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigView>,
+    /// The locking and unlocking scripts, as hex-encoded bincode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MultisigSignatureView {
+    /// The index of the signing key
+    pub key: u8,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MultisigView {
+    pub threshold: u8,
+    pub pub_keys: Vec<String>,
+    pub signatures: Vec<MultisigSignatureView>,
+}
+
+impl From<&Multisig> for MultisigView {
+    fn from(multisig: &Multisig) -> Self {
+        MultisigView {
+            threshold: multisig.threshold,
+            pub_keys: multisig.pub_keys.iter().map(|x| x.to_string()).collect(),
+            signatures: multisig
+                .signatures
+                .iter()
+                .map(|(key, x)| MultisigSignatureView {
+                    key: *key,
+                    signature: signature(x),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl MultisigView {
+    fn parse(&self) -> Result<Multisig, String> {
+        let pub_keys = self
+            .pub_keys
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<H256>, String>>()?;
+        let signatures = self
+            .signatures
+            .iter()
+            .map(|x| Ok((x.key, parse_signature(&x.signature)?)))
+            .collect::<Result<Vec<(u8, [H256; 2])>, String>>()?;
+        Ok(Multisig {
+            threshold: self.threshold,
+            pub_keys,
+            signatures,
+        })
+    }
+}
+
+impl From<&SignedTransaction> for TransactionView {
+    fn from(transaction: &SignedTransaction) -> Self {
+        TransactionView {
+            hash: transaction.hash().to_string(),
+            input: address(&transaction.input),
+            output: address(&transaction.output),
+            amount: transaction.amount,
+            lock_time: transaction.lock_time,
+            nonce: transaction.nonce,
+            pub_key: transaction.pub_key.to_string(),
+            signature: signature(&transaction.signature),
+            multisig: transaction.multisig.as_ref().map(MultisigView::from),
+            script: transaction
+                .script
+                .as_ref()
+                .map(|x| hex::encode(bincode::serialize(x).unwrap())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BlockView {
+    pub height: u32,
+    pub in_longest_chain: bool,
+    pub header: HeaderView,
+    pub transactions: Vec<TransactionView>,
+}
+
+impl BlockView {
+    /// The view of a block in the blockchain
+    pub fn new(block: &Block, blockchain: &Blockchain) -> Self {
+        let hash = block.hash();
+        let height = blockchain.height(&hash).unwrap_or(0);
+        BlockView {
+            height,
+            in_longest_chain: blockchain.hash_at_height(height) == Some(hash),
+            header: HeaderView::from(&block.header),
+            transactions: block
+                .content
+                .data
+                .iter()
+                .map(TransactionView::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TipView {
+    pub hash: String,
+    pub height: u32,
+}
+
+#[derive(Serialize)]
+pub struct ForkView {
+    pub tip: String,
+    pub fork_point: String,
+    pub fork_height: u32,
+    pub length: u32,
+}
+
+impl From<&Fork> for ForkView {
+    fn from(fork: &Fork) -> Self {
+        ForkView {
+            tip: fork.tip.to_string(),
+            fork_point: fork.fork_point.to_string(),
+            fork_height: fork.fork_height,
+            length: fork.length,
+        }
+    }
+}
+
+/// A signed transaction submitted as JSON, in the format of `TransactionView`. A multisig spend
+/// has zero `pub_key` and `signature`.
+#[derive(Deserialize)]
+struct TransactionRequest {
+    input: String,
+    output: String,
+    amount: f32,
+    #[serde(default)]
+    lock_time: u64,
+    #[serde(default)]
+    nonce: u64,
+    pub_key: String,
+    signature: String,
+    #[serde(default)]
+    multisig: Option<MultisigView>,
+    #[serde(default)]
+    script: Option<String>,
+}
+
+/// Parse a signed transaction given either as JSON or as hex-encoded bincode.
+pub fn parse_transaction(body: &str) -> Result<SignedTransaction, String> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        let request: TransactionRequest =
+            serde_json::from_str(body).map_err(|e| format!("error parsing JSON: {}", e))?;
+        Ok(SignedTransaction {
+            input: Address::parse_for_network(&request.input)
+                .map_err(|e| format!("input: {}", e))?,
+            output: Address::parse_for_network(&request.output)
+                .map_err(|e| format!("output: {}", e))?,
+            amount: request.amount,
+            lock_time: request.lock_time,
+            nonce: request.nonce,
+            pub_key: request
+                .pub_key
+                .parse()
+                .map_err(|e| format!("pub_key: {}", e))?,
+            signature: parse_signature(&request.signature)
+                .map_err(|e| format!("signature: {}", e))?,
+            multisig: match request.multisig {
+                Some(multisig) => Some(multisig.parse().map_err(|e| format!("multisig: {}", e))?),
+                None => None,
+            },
+            script: match request.script {
+                Some(script) => {
+                    let bytes = hex::decode(script).map_err(|e| format!("script: {}", e))?;
+                    Some(bincode::deserialize(&bytes).map_err(|e| format!("script: {}", e))?)
+                }
+                None => None,
+            },
+        })
+    } else {
+        let bytes = hex::decode(body).map_err(|e| format!("error parsing hex: {}", e))?;
+        bincode::deserialize(&bytes).map_err(|e| format!("error decoding transaction: {}", e))
+    }
+}
+
+/// Where a transaction is
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum TransactionStatus {
+    /// In the mempool
+    Pending,
+    /// In a block of the longest chain
+    Confirmed {
+        block: String,
+        confirmations: u32,
+    },
+    Unknown,
+}
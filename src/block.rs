@@ -4,7 +4,7 @@ use chrono::{DateTime,Utc};
 use crate::transaction::{Transaction,SignedTransaction};
 use rand::Rng;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use crate::crypto::merkle::{MerkleNode,MerkleTree};
+use crate::crypto::merkle::MerkleTree;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Block {
@@ -55,7 +55,9 @@ pub mod test {
         let sign1: H256 = sig1.into();
         let sign2: H256 = sig2.into();
         let signature: [H256;2] = [sign1,sign2];
-        let genesis_transaction = SignedTransaction{input: String::from("This is random faltugiri"), output: String::from("Blockchain"), amount: 0.00, signature: signature};
+        let addr: [u8;20] = rng.gen();
+        let pub_key: [u8;32] = rng.gen();
+        let genesis_transaction = SignedTransaction{input: addr.into(), output: addr.into(), amount: 0.00, lock_time: 0, nonce: 0, pub_key: pub_key.into(), signature, multisig: None, script: None};
         let timestamp: u128 = SystemTime::now().duration_since(UNIX_EPOCH).expect("dafuq").as_millis();
         let difficulty: H256 = (hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
         let mut data: Vec<SignedTransaction> = Vec::new();
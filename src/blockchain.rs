@@ -1,26 +1,60 @@
 use crate::block::{Header,Content,Block};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, H160, Hashable};
+use crate::crypto::address::{self, Network};
 //use crate::crypto::address::H160;
 use chrono::{DateTime,Utc};
 use crate::transaction::{Transaction,SignedTransaction};
+use crate::script::Context;
 use rand::Rng;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use crate::crypto::merkle::{MerkleNode,MerkleTree};
+use crate::crypto::merkle::MerkleTree;
 use hex_literal::hex;
 use std::convert::TryInto;
+use std::sync::OnceLock;
+
+/// The number of blocks whose median timestamp a new block must be later than
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in the future a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// The identifier of this node's chain, signed into every transaction. Each network has its
+/// own genesis block, so a transaction signed for one network is invalid on the others.
+pub fn chain_id() -> H256 {
+    static CHAIN_ID: OnceLock<H256> = OnceLock::new();
+    *CHAIN_ID.get_or_init(|| Blockchain::new().chain_id())
+}
+
+/// A side branch: a chain of blocks off the longest chain
+pub struct Fork {
+    /// The last block of the branch
+    pub tip: H256,
+    /// The block of the longest chain the branch starts from
+    pub fork_point: H256,
+    pub fork_height: u32,
+    /// The number of blocks in the branch
+    pub length: u32,
+}
 
 pub struct Blockchain {
     pub blockMap : HashMap<H256,Block>,
     pub tip : H256,
-    pub chainLength : u32
+    pub chainLength : u32,
+    genesis : H256,
+    /// The next nonce of each account that sent transactions in the longest chain
+    nonces : HashMap<H160,u64>
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of this node's network, only containing the genesis block
     pub fn new() -> Self {
-        
+        Self::for_network(address::network())
+    }
+
+    /// Create a new blockchain only containing the genesis block of a network
+    pub fn for_network(network: Network) -> Self {
+
         let sign1: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000000")).into();
         let sign2: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000001")).into();
         let pub_key: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000002")).into();
@@ -28,8 +62,12 @@ impl Blockchain {
         let address_gen: [u8;32] =  pub_key.hash().into();//(hex!("0000000000000000000000000000000000000000")).into();
         let truncated_addr: [u8;20] = (&address_gen[12..]).try_into().unwrap();
 
-        let genesis_transaction = SignedTransaction{input: truncated_addr.into(), output: truncated_addr.into(), amount: 0.00, pub_key: pub_key, signature: signature};
-        let nonce: u32 = 0;
+        let genesis_transaction = SignedTransaction{input: truncated_addr.into(), output: truncated_addr.into(), amount: 0.00, lock_time: 0, nonce: 0, pub_key: pub_key, signature: signature, multisig: None, script: None};
+        // the networks differ in the nonce of their genesis block, and so in chain ID
+        let nonce: u32 = match network {
+            Network::Main => 0,
+            Network::Test => 1,
+        };
         let timestamp: u128 = UNIX_EPOCH.duration_since(UNIX_EPOCH).expect("dafuq").as_millis();
         let difficulty: H256 = (hex!("3fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
         let mut data: Vec<SignedTransaction> = Vec::new();
@@ -47,8 +85,14 @@ impl Blockchain {
         //let mut leaves: Vec<H256> = Vec::new();
         //leaves.push(genHash);
 
-        let mut tip: H256 = genHash;
-        Blockchain{blockMap: blockMap, tip: tip, chainLength: 1}
+        let mut blockchain = Blockchain{blockMap: blockMap, tip: genHash, chainLength: 1, genesis: genHash, nonces: HashMap::new()};
+        blockchain.nonces = blockchain.count_nonces(&genHash);
+        blockchain
+    }
+
+    /// The identifier of the chain, signed into every transaction: the hash of its genesis block
+    pub fn chain_id(&self) -> H256 {
+        self.genesis
     }
 
     /// Insert a block into blockchain
@@ -67,6 +111,14 @@ impl Blockchain {
             count = count + 1;
         }
         if count > self.chainLength {
+            if block.header.parent == self.tip {
+                for transaction in block.content.data.iter() {
+                    *self.nonces.entry(transaction.input).or_insert(0) += 1;
+                }
+            } else {
+                // a reorg, count again along the new longest chain
+                self.nonces = self.count_nonces(&blockHash);
+            }
             self.chainLength = count;
             self.tip = blockHash;
         }
@@ -93,6 +145,211 @@ impl Blockchain {
 
         hashVec
     }
+
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u32> {
+        let mut height = 0;
+        let mut thisParent: H256 = self.blockMap.get(hash)?.header.parent;
+        while thisParent != H256::default() {
+            thisParent = self.blockMap[&thisParent].header.parent;
+            height += 1;
+        }
+        Some(height)
+    }
+
+    /// Get the median timestamp of a block and its ancestors, up to `MEDIAN_TIME_SPAN` blocks, or
+    /// 0 for an unknown block
+    pub fn median_time_past(&self, hash: &H256) -> u128 {
+        let mut timestamps: Vec<u128> = Vec::new();
+        let mut thisHash: H256 = *hash;
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.blockMap.get(&thisHash) {
+                Some(block) => {
+                    timestamps.push(block.header.timestamp);
+                    thisHash = block.header.parent;
+                }
+                None => break,
+            }
+        }
+        timestamps.sort();
+        timestamps.get(timestamps.len() / 2).cloned().unwrap_or_default()
+    }
+
+    /// Check the content, timestamp and lock times of a block whose parent is in the blockchain.
+    /// The transactions must be distinct and match the Merkle root, the timestamp must be later
+    /// than the median time past of the parent and at most `MAX_FUTURE_BLOCK_TIME` ahead of now,
+    /// and every transaction must be final with the next nonce of its account.
+    pub fn check_block(&self, block: &Block) -> Result<(), String> {
+        let parent = &block.header.parent;
+        if !self.blockMap.contains_key(parent) {
+            return Err(format!("unknown parent {}", parent));
+        }
+        // the Merkle tree of a list with a repeated transaction has its own root, but such a
+        // block is invalid anyway
+        let mut hashes: HashSet<H256> = HashSet::new();
+        if let Some(transaction) = block.content.data.iter().find(|x| !hashes.insert(x.hash())) {
+            return Err(format!("transaction {} is included twice", transaction.hash()));
+        }
+        if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+            return Err("Merkle root mismatch".to_string());
+        }
+        let medianTime = self.median_time_past(parent);
+        if block.header.timestamp <= medianTime {
+            return Err(format!("timestamp {} is not after the median time past {}", block.header.timestamp, medianTime));
+        }
+        let now: u128 = SystemTime::now().duration_since(UNIX_EPOCH).expect("dafuq").as_millis();
+        if block.header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(format!("timestamp {} is too far in the future", block.header.timestamp));
+        }
+        // lock times are checked against the median time past rather than the timestamp, which
+        // the miner chooses
+        let context = Context::after(self, parent);
+        if let Some(transaction) = block.content.data.iter().find(|x| !x.is_final(&context)) {
+            return Err(format!("transaction {} is locked until {}", transaction.hash(), transaction.lock_time));
+        }
+        // each account spends with consecutive nonces, so a transaction cannot be replayed
+        let mut nonces = self.next_nonces(parent, block.content.data.iter().map(|x| x.input).collect());
+        for transaction in block.content.data.iter() {
+            let nonce = nonces.get_mut(&transaction.input).unwrap();
+            if transaction.nonce != *nonce {
+                return Err(format!("transaction {} has nonce {} instead of {}", transaction.hash(), transaction.nonce, nonce));
+            }
+            *nonce += 1;
+        }
+        Ok(())
+    }
+
+    /// Get the next nonce of each account after a block: the number of transactions it sent in
+    /// the chain ending there. Only the chain ending at the tip is not walked.
+    pub fn next_nonces(&self, hash: &H256, accounts: HashSet<H160>) -> HashMap<H160, u64> {
+        if *hash == self.tip {
+            return accounts.into_iter().map(|x| (x, self.nonces.get(&x).cloned().unwrap_or(0))).collect();
+        }
+        let mut nonces: HashMap<H160, u64> = accounts.into_iter().map(|x| (x, 0)).collect();
+        let mut thisHash: H256 = *hash;
+        while let Some(block) = self.blockMap.get(&thisHash) {
+            for transaction in block.content.data.iter() {
+                if let Some(nonce) = nonces.get_mut(&transaction.input) {
+                    *nonce += 1;
+                }
+            }
+            thisHash = block.header.parent;
+        }
+        nonces
+    }
+
+    /// Get the next nonce of an account after the tip of the longest chain
+    pub fn next_nonce(&self, account: &H160) -> u64 {
+        self.nonces.get(account).cloned().unwrap_or(0)
+    }
+
+    /// Count the transactions each account sent in the chain ending at a block
+    fn count_nonces(&self, hash: &H256) -> HashMap<H160, u64> {
+        let mut nonces: HashMap<H160, u64> = HashMap::new();
+        let mut thisHash: H256 = *hash;
+        while let Some(block) = self.blockMap.get(&thisHash) {
+            for transaction in block.content.data.iter() {
+                *nonces.entry(transaction.input).or_insert(0) += 1;
+            }
+            thisHash = block.header.parent;
+        }
+        nonces
+    }
+
+    /// Get the hash of the block at the given height of the longest chain
+    pub fn hash_at_height(&self, height: u32) -> Option<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let height = height as usize;
+        if height >= chain.len() {
+            return None;
+        }
+        Some(chain[chain.len() - 1 - height])
+    }
+
+    /// Find the block of the longest chain including a transaction, with its height
+    pub fn find_transaction(&self, hash: &H256) -> Option<(H256, u32)> {
+        let chain = self.all_blocks_in_longest_chain();
+        let height = chain.len() as u32 - 1;
+        chain.iter().enumerate().find_map(|(depth, blockHash)| {
+            let block = &self.blockMap[blockHash];
+            if block.content.data.iter().any(|x| x.hash() == *hash) {
+                Some((*blockHash, height - depth as u32))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the side branches, i.e. the chains ending in a block with no child that is not the tip
+    pub fn forks(&self) -> Vec<Fork> {
+        let longest: HashMap<H256, u32> = self
+            .all_blocks_in_longest_chain()
+            .into_iter()
+            .rev()
+            .enumerate()
+            .map(|(height, hash)| (hash, height as u32))
+            .collect();
+        let parents: HashSet<H256> = self.blockMap.values().map(|x| x.header.parent).collect();
+        let mut forks: Vec<Fork> = Vec::new();
+        for leaf in self.blockMap.keys() {
+            if parents.contains(leaf) || longest.contains_key(leaf) {
+                continue;
+            }
+            let mut length = 0;
+            let mut hash = *leaf;
+            while !longest.contains_key(&hash) {
+                hash = self.blockMap[&hash].header.parent;
+                length += 1;
+            }
+            forks.push(Fork {
+                tip: *leaf,
+                fork_point: hash,
+                fork_height: longest[&hash],
+                length,
+            });
+        }
+        forks.sort_by_key(|x| (x.fork_height, x.tip));
+        forks
+    }
+
+    /// Build a block locator for the longest chain: the latest hashes one by one, then stepping
+    /// back exponentially, always ending with the genesis block
+    pub fn block_locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator: Vec<H256> = Vec::new();
+        let mut index: usize = 0;
+        let mut step: usize = 1;
+        while index < chain.len() {
+            locator.push(chain[index]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index += step;
+        }
+        let genesis = chain[chain.len() - 1];
+        if locator[locator.len() - 1] != genesis {
+            locator.push(genesis);
+        }
+        locator
+    }
+
+    /// Get at most `max` headers of the longest chain following the first locator hash that is
+    /// in the longest chain, or following the genesis block if none is
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let mut chain = self.all_blocks_in_longest_chain();
+        chain.reverse();
+        // the locator is the peer's, so look its hashes up rather than scanning the chain for each
+        let heights: HashMap<&H256, usize> = chain.iter().enumerate().map(|(height, hash)| (hash, height)).collect();
+        let start = locator
+            .iter()
+            .find_map(|hash| heights.get(hash).cloned())
+            .unwrap_or(0);
+        chain[start + 1..]
+            .iter()
+            .take(max)
+            .map(|hash| self.blockMap[hash].header.clone())
+            .collect()
+    }
 }
 
 #[cfg(any(test, test_utilities))]
@@ -110,4 +367,155 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn locator_and_headers() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut parent = genesis_hash;
+        for _ in 0..30 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let locator = blockchain.block_locator();
+        assert_eq!(locator[0], blockchain.tip());
+        assert_eq!(&locator[..10], &chain[..10]);
+        assert_eq!(locator[locator.len() - 1], genesis_hash);
+        assert!(locator.len() < chain.len());
+
+        let headers = blockchain.headers_after(&[chain[20]], 5);
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers[0].parent, chain[20]);
+        assert_eq!(headers[4].hash(), chain[15]);
+
+        let headers = blockchain.headers_after(&[H256::default()], 100);
+        assert_eq!(headers.len(), 30);
+        assert_eq!(headers[0].parent, genesis_hash);
+    }
+
+    /// Check a block after updating its Merkle root to its transactions
+    fn check_sealed(blockchain: &Blockchain, block: &Block) -> Result<(), String> {
+        let mut block = block.clone();
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        blockchain.check_block(&block)
+    }
+
+    #[test]
+    fn median_time_past() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        // timestamps out of order, within the rule
+        for timestamp in [5u128, 3, 8, 4, 9, 6, 7, 10, 11, 2, 12, 13].iter() {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = *timestamp;
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        // the last 11 are 3, 8, 4, 9, 6, 7, 10, 11, 2, 12, 13
+        assert_eq!(blockchain.median_time_past(&parent), 8);
+        let mut block = generate_random_block(&parent);
+        block.header.timestamp = 8;
+        assert!(check_sealed(&blockchain, &block).is_err());
+        block.header.timestamp = 9;
+        assert!(check_sealed(&blockchain, &block).is_ok());
+        block.header.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() + MAX_FUTURE_BLOCK_TIME + 60_000;
+        assert!(check_sealed(&blockchain, &block).is_err());
+
+        // the block is at height 13 and its lock times are checked against time 8
+        block.header.timestamp = 9;
+        block.content.data[0].lock_time = 13;
+        assert!(check_sealed(&blockchain, &block).is_ok());
+        block.content.data[0].lock_time = 14;
+        assert!(check_sealed(&blockchain, &block).is_err());
+        block.content.data[0].lock_time = crate::transaction::LOCK_TIME_THRESHOLD;
+        assert!(check_sealed(&blockchain, &block).is_err());
+    }
+
+    #[test]
+    fn nonces() {
+        let mut blockchain = Blockchain::new();
+        let account = H160::from([3; 20]);
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.data[0].input = account;
+        block.content.data.push(block.content.data[0].clone());
+        // the same transaction twice
+        assert!(check_sealed(&blockchain, &block).is_err());
+        block.content.data[1].nonce = 1;
+        assert!(check_sealed(&blockchain, &block).is_ok());
+        assert_eq!(blockchain.check_block(&block), Err("Merkle root mismatch".to_string()));
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        blockchain.insert(&block);
+        assert_eq!(blockchain.next_nonce(&account), 2);
+
+        // replaying a confirmed transaction in a later block
+        let mut replay = generate_random_block(&block.hash());
+        replay.header.timestamp = block.header.timestamp + 1;
+        replay.content.data[0] = block.content.data[1].clone();
+        assert!(check_sealed(&blockchain, &replay).is_err());
+        replay.content.data[0].nonce = 2;
+        assert!(check_sealed(&blockchain, &replay).is_ok());
+
+        // a longer branch without the account's transactions takes over, then the first one
+        // takes back over
+        let side = generate_random_block(&block.header.parent);
+        blockchain.insert(&side);
+        blockchain.insert(&generate_random_block(&side.hash()));
+        assert_eq!(blockchain.next_nonce(&account), 0);
+        let mut parent = block.hash();
+        for _ in 0..2 {
+            let child = generate_random_block(&parent);
+            blockchain.insert(&child);
+            parent = child.hash();
+        }
+        assert_eq!(blockchain.tip(), parent);
+        assert_eq!(blockchain.next_nonce(&account), 2);
+        assert_eq!(blockchain.nonces, blockchain.count_nonces(&parent));
+    }
+
+    #[test]
+    fn networks_have_their_own_chain() {
+        let main = Blockchain::for_network(Network::Main);
+        let test = Blockchain::for_network(Network::Test);
+        assert_ne!(main.chain_id(), test.chain_id());
+        assert_eq!(chain_id(), main.chain_id());
+    }
+
+    #[test]
+    fn heights_and_forks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut chain = vec![genesis_hash];
+        for _ in 0..5 {
+            let block = generate_random_block(&chain[chain.len() - 1]);
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+        assert!(blockchain.forks().is_empty());
+        // a branch of two blocks off height 2
+        let side = generate_random_block(&chain[2]);
+        blockchain.insert(&side);
+        let side_tip = generate_random_block(&side.hash());
+        blockchain.insert(&side_tip);
+
+        assert_eq!(blockchain.height(&genesis_hash), Some(0));
+        assert_eq!(blockchain.height(&side_tip.hash()), Some(4));
+        assert_eq!(blockchain.height(&H256::default()), None);
+        assert_eq!(blockchain.hash_at_height(0), Some(genesis_hash));
+        assert_eq!(blockchain.hash_at_height(5), Some(chain[5]));
+        assert_eq!(blockchain.hash_at_height(6), None);
+        assert_eq!(blockchain.hash_at_height(u32::MAX), None);
+
+        let forks = blockchain.forks();
+        assert_eq!(forks.len(), 1);
+        assert_eq!(forks[0].tip, side_tip.hash());
+        assert_eq!(forks[0].fork_point, chain[2]);
+        assert_eq!(forks[0].fork_height, 2);
+        assert_eq!(forks[0].length, 2);
+
+        let transaction = blockchain.blockMap[&chain[3]].content.data[0].hash();
+        assert_eq!(blockchain.find_transaction(&transaction), Some((chain[3], 3)));
+        assert_eq!(blockchain.find_transaction(&side.content.data[0].hash()), None);
+    }
 }
@@ -1,118 +1,170 @@
-use serde::{Serialize, Deserialize};
+//! Checksummed text addresses.
+//!
+//! An address is written in Base58Check: a version byte naming the network, the 20 bytes of the
+//! `H160`, and the first 4 bytes of the double SHA256 of both, in base 58. A typo changes the
+//! checksum, so it is caught before a transaction is signed.
+
+use crate::crypto::hash::H160;
+
 use std::convert::TryInto;
-use crate::crypto::hash::{H256, Hashable};
+use std::sync::OnceLock;
 
-/// A SHA256 hash.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
-pub struct H160([u8; 20]); // big endian u256
+const CHECKSUM_LEN: usize = 4;
 
-impl Hashable for H160 {
-    fn hash(&self) -> H256 {
-        ring::digest::digest(&ring::digest::SHA256, &self.0).into()
-    }
+/// The network an address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
 }
 
-impl std::fmt::Display for H160 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let start = if let Some(precision) = f.precision() {
-            if precision >= 64 {
-                0
-            } else {
-                32 - precision / 2
-            }
-        } else {
-            0
-        };
-        for byte_idx in start..32 {
-            write!(f, "{:>02x}", &self.0[byte_idx])?;
+static NETWORK: OnceLock<Network> = OnceLock::new();
+
+/// Choose the network of this node at startup. Fails with the network in use if another one was
+/// chosen, or already used, before.
+pub fn set_network(network: Network) -> Result<(), Network> {
+    NETWORK.set(network).or_else(|_| match self::network() {
+        current if current == network => Ok(()),
+        current => Err(current),
+    })
+}
+
+/// The network of this node, the main network unless another one was chosen
+pub fn network() -> Network {
+    *NETWORK.get_or_init(|| Network::Main)
+}
+
+impl Network {
+    fn version(self) -> u8 {
+        match self {
+            Network::Main => 0x00,
+            Network::Test => 0x6f,
         }
-        Ok(())
     }
-}
 
-impl std::fmt::Debug for H160 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "{:>02x}{:>02x}..{:>02x}{:>02x}",
-            &self.0[0], &self.0[1], &self.0[18], &self.0[19]
-        )
+    fn from_version(version: u8) -> Option<Self> {
+        [Network::Main, Network::Test]
+            .iter()
+            .cloned()
+            .find(|x| x.version() == version)
     }
 }
 
-impl std::convert::AsRef<[u8]> for H160 {
-    fn as_ref(&self) -> &[u8] {
-        &self.0
-    }
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let once = ring::digest::digest(&ring::digest::SHA256, payload);
+    let twice = ring::digest::digest(&ring::digest::SHA256, once.as_ref());
+    twice.as_ref()[..CHECKSUM_LEN].try_into().unwrap()
 }
 
-impl std::convert::From<&[u8; 20]> for H160 {
-    fn from(input: &[u8; 20]) -> H160 {
-        let mut buffer: [u8; 20] = [0; 20];
-        buffer[..].copy_from_slice(input);
-        H160(buffer)
-    }
+/// An `H160` with the network it is meant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub network: Network,
+    pub hash: H160,
 }
 
-impl std::convert::From<&H160> for [u8; 20] {
-    fn from(input: &H160) -> [u8; 20] {
-        let mut buffer: [u8; 20] = [0; 20];
-        buffer[..].copy_from_slice(&input.0);
-        buffer
+impl Address {
+    /// The address of a hash on this node's network
+    pub fn new(hash: H160) -> Self {
+        Address {
+            network: network(),
+            hash,
+        }
     }
-}
 
-impl std::convert::From<[u8; 20]> for H160 {
-    fn from(input: [u8; 20]) -> H160 {
-        H160(input)
+    /// The hash of an address, which must be meant for this node's network
+    pub fn parse_for_network(s: &str) -> Result<H160, String> {
+        let address: Address = s.parse()?;
+        if address.network != network() {
+            return Err(format!(
+                "address is for the {:?} network, not {:?}",
+                address.network,
+                network()
+            ));
+        }
+        Ok(address.hash)
     }
 }
 
-impl std::convert::From<H160> for [u8; 20] {
-    fn from(input: H160) -> [u8; 20] {
-        input.0
+impl From<H160> for Address {
+    fn from(hash: H160) -> Self {
+        Address::new(hash)
     }
 }
 
-impl std::convert::From<ring::digest::Digest> for H160 {
-    fn from(input: ring::digest::Digest) -> H160 {
-        let mut raw_hash: [u8; 20] = [0; 20];
-        raw_hash[0..20].copy_from_slice(input.as_ref());
-        H160(raw_hash)
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let payload = [&[self.network.version()][..], self.hash.as_ref()].concat();
+        let encoded = bs58::encode([&payload[..], &checksum(&payload)].concat()).into_string();
+        write!(f, "{}", encoded)
     }
 }
 
-impl Ord for H160 {
-    fn cmp(&self, other: &H160) -> std::cmp::Ordering {
-        let self_higher = u128::from_be_bytes(self.0[0..10].try_into().unwrap());
-        let self_lower = u128::from_be_bytes(self.0[10..20].try_into().unwrap());
-        let other_higher = u128::from_be_bytes(other.0[0..10].try_into().unwrap());
-        let other_lower = u128::from_be_bytes(other.0[20..20].try_into().unwrap());
-        let higher = self_higher.cmp(&other_higher);
-        match higher {
-            std::cmp::Ordering::Equal => self_lower.cmp(&other_lower),
-            _ => higher,
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|e| format!("invalid address: {}", e))?;
+        if bytes.len() != 1 + 20 + CHECKSUM_LEN {
+            return Err(format!("invalid address length {}", bytes.len()));
+        }
+        let (payload, check) = bytes.split_at(1 + 20);
+        if checksum(payload) != check {
+            return Err("invalid address checksum".to_string());
         }
+        let network = Network::from_version(payload[0])
+            .ok_or_else(|| format!("unknown address version {}", payload[0]))?;
+        let hash: [u8; 20] = payload[1..].try_into().unwrap();
+        Ok(Address {
+            network,
+            hash: hash.into(),
+        })
     }
 }
 
-impl PartialOrd for H160 {
-    fn partial_cmp(&self, other: &H160) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[cfg(any(test, test_utilities))]
-pub mod tests {
-    use super::H160;
-    use rand::Rng;
-
-    pub fn generate_random_hash() -> H160 {
-        let mut rng = rand::thread_rng();
-        let random_bytes: Vec<u8> = (0..20).map(|_| rng.gen()).collect();
-        let mut raw_bytes = [0; 20];
-        raw_bytes.copy_from_slice(&random_bytes);
-        (&raw_bytes).into()
+    #[test]
+    fn encode_and_parse() {
+        // the Bitcoin address of hash160 0x00..00, which shares the format
+        let zero = Address::new(H160::from([0; 20]));
+        assert_eq!(zero.to_string(), "1111111111111111111114oLvT2");
+        let hash = H160::from([0x5a; 20]);
+        let address = Address::new(hash);
+        let text = address.to_string();
+        assert_eq!(text.parse::<Address>(), Ok(address));
+        assert_eq!(Address::parse_for_network(&text), Ok(hash));
+
+        // every single character typo is caught
+        for i in 0..text.len() {
+            for c in "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz".chars() {
+                let mut typo: Vec<char> = text.chars().collect();
+                if typo[i] == c {
+                    continue;
+                }
+                typo[i] = c;
+                let typo: String = typo.into_iter().collect();
+                assert!(typo.parse::<Address>().is_err(), "{} accepted", typo);
+            }
+        }
+        let test = Address {
+            network: Network::Test,
+            hash,
+        };
+        assert!(Address::parse_for_network(&test.to_string()).is_err());
+        assert!("0OIl".parse::<Address>().is_err());
     }
 
+    #[test]
+    fn network_is_chosen_once() {
+        // the tests run on the main network, which is in use once an address was made
+        Address::new(H160::from([0; 20]));
+        assert_eq!(set_network(Network::Main), Ok(()));
+        assert_eq!(set_network(Network::Test), Err(Network::Main));
+        assert_eq!(network(), Network::Main);
+    }
 }
@@ -35,6 +35,20 @@ impl std::fmt::Display for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from its 64 hex digits, as printed by `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let raw: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("expected 32 bytes, got {}", bytes.len()))?;
+        Ok(H256(raw))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -120,14 +134,26 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn h160_display_and_order() {
+        let mut raw = [0u8; 20];
+        raw[19] = 0xab;
+        let low = super::H160::from(raw);
+        assert_eq!(low.to_string(), format!("{}ab", "00".repeat(19)));
+        assert_eq!(format!("{:.4}", low), "00ab");
+        raw[0] = 1;
+        let high = super::H160::from(raw);
+        assert!(low < high);
+        assert_eq!(high.to_string().parse::<super::H160>(), Ok(high));
+    }
 }
 
 
 
 
-/// A SHA256 hash.
+/// A 160-bit hash, used as an address.
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
-pub struct H160([u8; 20]); // big endian u256
+pub struct H160([u8; 20]); // big endian u160
 
 impl Hashable for H160 {
     fn hash(&self) -> H256 {
@@ -138,21 +164,35 @@ impl Hashable for H160 {
 impl std::fmt::Display for H160 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
-            if precision >= 64 {
+            if precision >= 40 {
                 0
             } else {
-                32 - precision / 2
+                20 - precision / 2
             }
         } else {
             0
         };
-        for byte_idx in start..32 {
+        for byte_idx in start..20 {
             write!(f, "{:>02x}", &self.0[byte_idx])?;
         }
         Ok(())
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    /// Parse an address from its 40 hex digits.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let raw: [u8; 20] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("expected 20 bytes, got {}", bytes.len()))?;
+        Ok(H160(raw))
+    }
+}
+
 impl std::fmt::Debug for H160 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -207,15 +247,7 @@ impl std::convert::From<ring::digest::Digest> for H160 {
 
 impl Ord for H160 {
     fn cmp(&self, other: &H160) -> std::cmp::Ordering {
-        let self_higher = u128::from_be_bytes(self.0[0..10].try_into().unwrap());
-        let self_lower = u128::from_be_bytes(self.0[10..20].try_into().unwrap());
-        let other_higher = u128::from_be_bytes(other.0[0..10].try_into().unwrap());
-        let other_lower = u128::from_be_bytes(other.0[20..20].try_into().unwrap());
-        let higher = self_higher.cmp(&other_higher);
-        match higher {
-            std::cmp::Ordering::Equal => self_lower.cmp(&other_lower),
-            _ => higher,
-        }
+        self.0.cmp(&other.0)
     }
 }
 
@@ -226,7 +258,7 @@ impl PartialOrd for H160 {
 }
 
 #[cfg(any(test, test_utilities))]
-pub mod tests {
+pub mod h160_tests {
     use super::H160;
     use rand::Rng;
 
@@ -239,4 +271,3 @@ pub mod tests {
     }
 
 }
-
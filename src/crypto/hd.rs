@@ -0,0 +1,175 @@
+//! Hierarchical deterministic keys and mnemonic seed phrases.
+//!
+//! Keys are derived from a seed following SLIP-0010 for Ed25519, which only defines hardened
+//! derivation: every index in a path is hardened. The seed comes from a BIP-39 mnemonic, whose
+//! last word carries a checksum of the others.
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::Ed25519KeyPair;
+
+/// The first hardened index
+pub const HARDENED: u32 = 0x8000_0000;
+/// The number of words of generated mnemonics
+const MNEMONIC_WORDS: usize = 24;
+/// The PKCS#8 v1 encoding of an Ed25519 private key, up to the key itself
+const PKCS8_V1_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// A private key with the chain code needed to derive its children
+#[derive(Clone)]
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn from_hmac(key: &[u8], data: &[u8]) -> Self {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, key), data);
+        let mut extended = ExtendedKey {
+            key: [0; 32],
+            chain_code: [0; 32],
+        };
+        extended.key.copy_from_slice(&tag.as_ref()[..32]);
+        extended.chain_code.copy_from_slice(&tag.as_ref()[32..]);
+        extended
+    }
+
+    /// The master key of a seed
+    pub fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(b"ed25519 seed", seed)
+    }
+
+    /// The hardened child at `index`, whether or not the index has the hardened bit set
+    pub fn child(&self, index: u32) -> Self {
+        let data = [&[0u8][..], &self.key, &(index | HARDENED).to_be_bytes()].concat();
+        Self::from_hmac(&self.chain_code, &data)
+    }
+
+    /// The key at a path below the master key of a seed
+    pub fn derive(seed: &[u8], path: &[u32]) -> Self {
+        path.iter()
+            .fold(Self::master(seed), |key, index| key.child(*index))
+    }
+
+    pub fn key_pair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&self.key).unwrap()
+    }
+
+    /// The private key as a PKCS#8 v1 document, which `Ed25519KeyPair::from_pkcs8_maybe_unchecked`
+    /// reads
+    pub fn pkcs8(&self) -> Vec<u8> {
+        [&PKCS8_V1_PREFIX[..], &self.key].concat()
+    }
+}
+
+/// Parse a path such as `m/44'/0'/1'`. Every index is hardened, so the `'` is optional.
+pub fn parse_path(path: &str) -> Result<Vec<u32>, String> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(format!("path {} does not start with m", path));
+    }
+    parts
+        .map(|part| {
+            let index = part
+                .trim_end_matches('\'')
+                .parse::<u32>()
+                .map_err(|e| format!("bad index {}: {}", part, e))?;
+            if index >= HARDENED {
+                return Err(format!("index {} out of range", index));
+            }
+            Ok(index)
+        })
+        .collect()
+}
+
+/// Generate a random mnemonic.
+pub fn generate_mnemonic() -> String {
+    let mut entropy = [0u8; MNEMONIC_WORDS * 4 / 3];
+    SystemRandom::new().fill(&mut entropy).unwrap();
+    bip39::Mnemonic::from_entropy(&entropy).unwrap().to_string()
+}
+
+/// Check the words and checksum of a mnemonic and return it in normal form.
+pub fn parse_mnemonic(phrase: &str) -> Result<String, String> {
+    bip39::Mnemonic::parse(phrase)
+        .map(|x| x.to_string())
+        .map_err(|e| format!("invalid mnemonic: {}", e))
+}
+
+/// The seed of a mnemonic, with an optional passphrase
+pub fn mnemonic_to_seed(phrase: &str, passphrase: &str) -> Result<[u8; 64], String> {
+    let mnemonic =
+        bip39::Mnemonic::parse(phrase).map_err(|e| format!("invalid mnemonic: {}", e))?;
+    Ok(mnemonic.to_seed(passphrase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::convertPubKeyToH160;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn slip10_vectors() {
+        // test vector 1 for ed25519 of SLIP-0010
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let vectors = [
+            (
+                "m",
+                "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+                "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed",
+            ),
+            (
+                "m/0'",
+                "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+                "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c",
+            ),
+            (
+                "m/0'/1'",
+                "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+                "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187",
+            ),
+        ];
+        for (path, private, public) in vectors.iter() {
+            let key = ExtendedKey::derive(&seed, &parse_path(path).unwrap());
+            assert_eq!(hex::encode(key.key), *private);
+            let key_pair = key.key_pair();
+            assert_eq!(hex::encode(key_pair.public_key()), *public);
+            let from_pkcs8 = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&key.pkcs8()).unwrap();
+            assert_eq!(
+                from_pkcs8.public_key().as_ref(),
+                key_pair.public_key().as_ref()
+            );
+            // an address is the last 20 bytes of the public key
+            let address = convertPubKeyToH160(key_pair.public_key());
+            assert_eq!(hex::encode(address), public[24..]);
+        }
+        assert!(parse_path("44'/0'").is_err());
+        assert!(parse_path("m/2147483648").is_err());
+    }
+
+    #[test]
+    fn mnemonics() {
+        // the first test vector of BIP-39
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let seed = mnemonic_to_seed(phrase, "TREZOR").unwrap();
+        assert_eq!(
+            hex::encode(&seed[..]),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        let key = ExtendedKey::derive(&seed, &parse_path("m/44'/0'/0'/0'/0'").unwrap());
+        assert_eq!(
+            hex::encode(convertPubKeyToH160(key.key_pair().public_key())),
+            "c8c70007f4f4ce9c51d4050c34c3ba6b20599864"
+        );
+
+        // the last word is a checksum
+        let bad = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon";
+        assert!(parse_mnemonic(bad).is_err());
+        let generated = generate_mnemonic();
+        assert_eq!(generated.split(' ').count(), MNEMONIC_WORDS);
+        assert_eq!(parse_mnemonic(&generated).unwrap(), generated);
+    }
+}
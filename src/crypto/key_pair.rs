@@ -1,5 +1,6 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::path::Path;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +8,18 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Load a key pair from a PKCS#8 file, or generate one and save it there if the file does not
+/// exist yet.
+pub fn load_or_generate(path: &Path) -> std::io::Result<Ed25519KeyPair> {
+    if path.exists() {
+        let pkcs8_bytes = std::fs::read(path)?;
+        return Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}", e))
+        });
+    }
+    let rng = rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    std::fs::write(path, pkcs8_bytes.as_ref())?;
+    Ok(Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap())
+}
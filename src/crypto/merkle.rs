@@ -1,114 +1,109 @@
 use super::hash::{Hashable, H256};
 
-/// A Merkle tree.
-#[derive(Debug, Default,Clone)]
+/// The first byte hashed with a leaf, so that no leaf hash is also the hash of an inner node
+const LEAF_PREFIX: u8 = 0x00;
+/// The first byte hashed with the children of an inner node
+const INNER_PREFIX: u8 = 0x01;
+
+/// A Merkle tree, stored as the hashes of each level from the leaves up to the root.
+///
+/// A leaf is the hash of a datum hash and an inner node the hash of its two children, each with
+/// its own prefix byte. The last node of a level with an odd number of nodes has no sibling and
+/// moves up to the next level unchanged, instead of being paired with a copy of itself, so a list
+/// of data with its last datum repeated has a different root.
+#[derive(Debug, Default, Clone)]
 pub struct MerkleTree {
-    root: MerkleNode,
-    node_array_by_levels: Vec<Vec<MerkleNode>>,
+    levels: Vec<Vec<H256>>,
 }
 
-#[derive(Debug, Default,Clone)]
-pub struct MerkleNode {
-    value: H256,
-    child1: Option<Box<MerkleNode>>,
-    child2: Option<Box<MerkleNode>>,
-    parent: Option<Box<MerkleNode>>,
-    is_leaf: bool,
+/// The hash of a leaf
+fn hash_leaf(datum: &H256) -> H256 {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(&[LEAF_PREFIX]);
+    ctx.update(datum.as_ref());
+    ctx.finish().into()
 }
 
-pub fn sibling_hash(node: &MerkleNode)-> H256{
-    let parent: MerkleNode = *(node.parent.clone().unwrap());
-    let child1: MerkleNode = *(parent.child1.clone().unwrap());
-    let child2: MerkleNode = *(parent.child2.clone().unwrap());
-    if child1.value == node.value {
-        child2.value
-    }else{
-        child1.value
-
-    }
+/// The hash of an inner node
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(&[INNER_PREFIX]);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    ctx.finish().into()
 }
 
 impl MerkleTree {
-
-    pub fn new<T:Hashable+Clone>(data: &[T]) -> MerkleTree {
-        let size_of_data = data.len();
-        let mut vector_data = data.to_vec();
-        if size_of_data % 2 == 1 {
-            vector_data.push(vector_data[size_of_data-1].clone());
-        }
-        let mut hashed_data = vec![];
-        for datum in vector_data {
-            let mut hashed_datum = MerkleNode{value: Hashable::hash(&datum),child1:None,child2:None,parent:None,is_leaf: true};
-            hashed_data.push(hashed_datum);
-        }
-        let mut prev_level: Vec<MerkleNode> = hashed_data;
-        let mut node_array_by_levels = vec![];
-        while prev_level.len() > 1 {
-            let mut size_of_level = prev_level.len();
-            if size_of_level % 2 == 1 {
-                let mut last = prev_level[size_of_level-1].clone();
-                last.is_leaf = true;
-                prev_level.push(last);
-            }
-            size_of_level = prev_level.len();
-            let mut this_level = vec![];
-            
-            for i in 0..size_of_level/2 {
-                let c1 : &[u8] = prev_level[2*i].value.as_ref();
-                let c2 : &[u8] = prev_level[2*i+1].value.as_ref();
-                let concatenated: &[u8] = &[c1,c2].concat();
-                let root: H256 = ring::digest::digest(&ring::digest::SHA256, &concatenated).into();
-                let mut new_node : MerkleNode = MerkleNode{value: root, child1: Some(Box::new(prev_level[2*i].clone())), child2: Some(Box::new(prev_level[2*i+1].clone())),parent:None,is_leaf:false};
-                this_level.push(new_node);
-                prev_level[2*i].parent = Some(Box::new(this_level[i].clone()));
-                prev_level[2*i+1].parent = Some(Box::new(this_level[i].clone()));
-            }
-            node_array_by_levels.push(prev_level.clone());
-            prev_level = this_level;
+    pub fn new<T: Hashable>(data: &[T]) -> MerkleTree {
+        let leaves: Vec<H256> = data.iter().map(|x| hash_leaf(&x.hash())).collect();
+        let mut levels: Vec<Vec<H256>> = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_pair(left, right),
+                    [odd] => *odd,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
         }
-        let mut root: MerkleNode = prev_level[0].clone();
-        MerkleTree{root: root, node_array_by_levels: node_array_by_levels}
+        MerkleTree { levels }
     }
-    
+
+    /// The root hash, zero for a tree without leaves
     pub fn root(&self) -> H256 {
-        self.root.value
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .cloned()
+            .unwrap_or_default()
     }
 
-    /// Returns the Merkle Proof of data at index i
+    /// Returns the Merkle Proof of data at index i: the sibling of the leaf and of each of its
+    /// ancestors that has one, from the bottom up. Panics if there is no datum at that index.
     pub fn proof(&self, index: usize) -> Vec<H256> {
-        
-        let mut result_vec = vec![];
-        let mut trail: MerkleNode = self.node_array_by_levels[0][index].clone();
-        for level in self.node_array_by_levels.clone() {
-            result_vec.push(sibling_hash(&trail));
-            trail = *(trail.parent.unwrap());
+        assert!(index < self.levels[0].len(), "no leaf at index {}", index);
+        let mut index = index;
+        let mut result_vec = Vec::with_capacity(self.levels.len() - 1);
+        for level in self.levels[..self.levels.len() - 1].iter() {
+            if let Some(sibling) = level.get(index ^ 1) {
+                result_vec.push(*sibling);
+            }
+            index /= 2;
         }
         result_vec
-
     }
 }
 
-
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
-/// index of datum and `leaf_size`, the total number of leaves.
+/// index of datum and `leaf_size`, the total number of leaves, which tells the levels where the
+/// node has no sibling. Fails if the index is out of range or the proof has extra hashes.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
-
-    let mut trail_hash: H256 = *datum;
+    if index >= leaf_size {
+        return false;
+    }
+    let mut trail_hash: H256 = hash_leaf(datum);
     let mut level_idx: usize = index;
-    for hash in proof {
-        let c1 : &[u8] = (*hash).as_ref();
-        let c2 : &[u8] = trail_hash.as_ref();
-
+    let mut level_size: usize = leaf_size;
+    let mut proof = proof.iter();
+    while level_size > 1 {
         if level_idx % 2 == 1 {
-            let concatenated = &[c1,c2].concat();
-            trail_hash = ring::digest::digest(&ring::digest::SHA256, &concatenated).into();
-        }else {
-            let concatenated = &[c2,c1].concat();
-            trail_hash = ring::digest::digest(&ring::digest::SHA256, &concatenated).into();
+            match proof.next() {
+                Some(hash) => trail_hash = hash_pair(hash, &trail_hash),
+                None => return false,
+            }
+        } else if level_idx + 1 < level_size {
+            match proof.next() {
+                Some(hash) => trail_hash = hash_pair(&trail_hash, hash),
+                None => return false,
+            }
         }
-        level_idx = level_idx/2;
+        level_idx /= 2;
+        level_size = level_size.div_ceil(2);
     }
-    (trail_hash == *root)
+    proof.next().is_none() && trail_hash == *root
 }
 
 #[cfg(test)]
@@ -132,14 +127,17 @@ mod tests {
         let root = merkle_tree.root();
         assert_eq!(
             root,
-            (hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920")).into()
+            (hex!("60253b9ff3bb53d93bedd4629c764ced1e1ff6520d9bf0ee715a1753a059feae")).into()
         );
         // "b69566be6e1720872f73651d1851a0eae0060a132cf0f64a0ffaea248de6cba0" is the hash of
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
         // "965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f" is the hash of
         // "0101010101010101010101010101010101010101010101010101010101010202"
-        // "6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920" is the hash of
-        // the concatenation of these two hashes "b69..." and "965..."
+        // "a67925cfca2d309c3b85f43f14cc3e0d932f616eac45098acd0b720d01ca2485" and
+        // "e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1" are the hashes of
+        // 00 followed by "b69..." and by "965...", the two leaves
+        // "60253b9ff3bb53d93bedd4629c764ced1e1ff6520d9bf0ee715a1753a059feae" is the hash of
+        // 01 followed by the two leaves "a67..." and "e12..."
         // notice that the order of these two matters
     }
 
@@ -149,9 +147,9 @@ mod tests {
         let merkle_tree = MerkleTree::new(&input_data);
         let proof = merkle_tree.proof(0);
         assert_eq!(proof,
-                   vec![hex!("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into()]
+                   vec![hex!("e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1").into()]
         );
-        // "965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f" is the hash of
+        // "e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1" is the leaf of
         // "0101010101010101010101010101010101010101010101010101010101010202"
     }
 
@@ -162,4 +160,44 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn every_size() {
+        assert_eq!(MerkleTree::new::<H256>(&[]).root(), H256::default());
+        let mut roots = std::collections::HashSet::new();
+        for size in 1..=33u8 {
+            let input_data: Vec<H256> = (0..size).map(|x| H256::from([x; 32])).collect();
+            let merkle_tree = MerkleTree::new(&input_data);
+            let root = merkle_tree.root();
+            assert!(roots.insert(root), "size {}", size);
+            for (index, datum) in input_data.iter().enumerate() {
+                let proof = merkle_tree.proof(index);
+                let leaf_size = input_data.len();
+                assert!(verify(&root, &datum.hash(), &proof, index, leaf_size));
+                let other = input_data[(index + 1) % leaf_size].hash();
+                assert!(size == 1 || !verify(&root, &other, &proof, index, leaf_size));
+                assert!(!verify(&root, &datum.hash(), &proof, index + leaf_size, leaf_size));
+                if !proof.is_empty() {
+                    let shorter = &proof[..proof.len() - 1];
+                    assert!(!verify(&root, &datum.hash(), shorter, index, leaf_size));
+                }
+                let mut longer = proof.clone();
+                longer.push(root);
+                assert!(!verify(&root, &datum.hash(), &longer, index, leaf_size));
+            }
+        }
+    }
+
+    #[test]
+    fn duplicated_leaves() {
+        // with the last datum repeated, as in CVE-2012-2459
+        let mut input_data: Vec<H256> = (0..3u8).map(|x| H256::from([x; 32])).collect();
+        let root = MerkleTree::new(&input_data).root();
+        input_data.push(input_data[2]);
+        assert_ne!(MerkleTree::new(&input_data).root(), root);
+        // a leaf cannot stand for an inner node
+        let tree = MerkleTree::new(&input_data[..2]);
+        let inner = tree.root();
+        assert!(!verify(&inner, &tree.levels[0][0], &[], 0, 1));
+    }
 }
@@ -1,3 +1,5 @@
+pub mod address;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
+pub mod hd;
@@ -0,0 +1,343 @@
+//! Hashed time-locked contracts.
+//!
+//! An HTLC pays to the address of a script with two branches: the recipient can spend it by
+//! revealing a preimage of its SHA256 hash, and the sender can take it back once the timeout is
+//! reached. Like a lock time, a timeout below `LOCK_TIME_THRESHOLD` is a block height, otherwise
+//! a time in milliseconds. The claim branch stays open after the timeout until a refund is
+//! confirmed, so the recipient should claim well before it.
+//!
+//! Two HTLCs locked with the same hash on two chains make an atomic swap: claiming one reveals
+//! the preimage on its chain, which lets the other party claim the other one. The party who
+//! chose the preimage locks its funds with the longer timeout.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256};
+use crate::script::{Op, Script, ScriptSpend};
+use crate::transaction::{self, SignedTransaction, Transaction, LOCK_TIME_THRESHOLD};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+
+/// The SHA256 of a preimage
+pub fn hash_preimage(preimage: &[u8]) -> H256 {
+    let hash: [u8; 32] = ring::digest::digest(&ring::digest::SHA256, preimage)
+        .as_ref()
+        .try_into()
+        .unwrap();
+    hash.into()
+}
+
+/// Generate a random preimage and its hash.
+pub fn generate_preimage() -> ([u8; 32], H256) {
+    let mut preimage = [0u8; 32];
+    SystemRandom::new().fill(&mut preimage).unwrap();
+    (preimage, hash_preimage(&preimage))
+}
+
+/// The terms of a hashed time-locked contract
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Htlc {
+    /// The SHA256 of the preimage the recipient must reveal
+    pub hash: H256,
+    /// The public key of the recipient
+    pub recipient: H256,
+    /// The public key of the sender, who gets the refund
+    pub sender: H256,
+    /// The height or time from which the sender can take the funds back
+    pub timeout: u64,
+}
+
+impl Htlc {
+    /// The locking script of the contract
+    pub fn script(&self) -> Script {
+        let timeout_check = if self.timeout < LOCK_TIME_THRESHOLD {
+            Op::CheckHeightVerify
+        } else {
+            Op::CheckTimeVerify
+        };
+        Script(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(self.hash.as_ref().to_vec()),
+            Op::EqualVerify,
+            Op::Push(self.recipient.as_ref().to_vec()),
+            Op::Else,
+            Op::Number(self.timeout),
+            timeout_check,
+            Op::Push(self.sender.as_ref().to_vec()),
+            Op::EndIf,
+            Op::CheckSig,
+        ])
+    }
+
+    /// The address funds are sent to, to lock them in the contract
+    pub fn address(&self) -> H160 {
+        self.script().address()
+    }
+
+    /// A transaction spending from the contract with the preimage, signed by the recipient.
+    pub fn claim(
+        &self,
+        raw: &Transaction,
+        preimage: &[u8],
+        key: &Ed25519KeyPair,
+    ) -> SignedTransaction {
+        let signature = transaction::sign(raw, key).as_ref().to_vec();
+        let unlocking = Script(vec![
+            Op::Push(signature),
+            Op::Push(preimage.to_vec()),
+            Op::Number(1),
+        ]);
+        self.spend(raw, unlocking)
+    }
+
+    /// A transaction spending from the contract after the timeout, signed by the sender.
+    pub fn refund(&self, raw: &Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+        let signature = transaction::sign(raw, key).as_ref().to_vec();
+        self.spend(raw, Script(vec![Op::Push(signature), Op::Number(0)]))
+    }
+
+    fn spend(&self, raw: &Transaction, unlocking: Script) -> SignedTransaction {
+        let spend = ScriptSpend {
+            locking: self.script(),
+            unlocking,
+        };
+        SignedTransaction::from_script(raw, spend)
+    }
+
+    /// The preimage revealed by a claim in the longest chain, if any
+    pub fn find_preimage(&self, blockchain: &Blockchain) -> Option<Vec<u8>> {
+        let address = self.address();
+        blockchain
+            .all_blocks_in_longest_chain()
+            .iter()
+            .flat_map(|hash| blockchain.blockMap[hash].content.data.iter())
+            .filter(|x| x.input == address)
+            .filter_map(|x| x.script.as_ref())
+            .flat_map(|spend| spend.unlocking.0.iter())
+            .find_map(|op| match op {
+                Op::Push(element) if hash_preimage(element) == self.hash => Some(element.clone()),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Content, Header};
+    use crate::crypto::key_pair;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::mempool::Mempool;
+    use crate::script::Context;
+    use crate::wallet::Wallet;
+    use ring::signature::KeyPair;
+
+    /// A node of one chain: its blockchain and mempool
+    struct Chain {
+        blockchain: Blockchain,
+        mempool: Mempool,
+    }
+
+    impl Chain {
+        fn new() -> Self {
+            Chain {
+                blockchain: Blockchain::new(),
+                mempool: Mempool::new(),
+            }
+        }
+
+        /// Validate a transaction as the API does and add it to the mempool.
+        fn submit(&mut self, transaction: SignedTransaction) -> Result<(), String> {
+            transaction.validate(&Context::next_block(&self.blockchain))?;
+            self.mempool.insert(transaction);
+            Ok(())
+        }
+
+        /// Mine the mempool into a block, with an optional payment to fund a wallet.
+        fn mine(&mut self, reward: Option<(H160, f32)>) {
+            let parent = self.blockchain.tip();
+            let mut data = self.mempool.oldest(100);
+            if let Some((to, amount)) = reward {
+                let (_, mut coinbase, _) = transaction::generate_random_signed_transaction();
+                coinbase.output = to;
+                coinbase.amount = amount;
+                data.push(coinbase);
+            }
+            if data.is_empty() {
+                data.push(transaction::generate_random_signed_transaction().1);
+            }
+            let header = Header {
+                parent,
+                nonce: 0,
+                difficulty: self.blockchain.blockMap[&parent].header.difficulty,
+                timestamp: self.blockchain.median_time_past(&parent) + 1,
+                merkle_root: MerkleTree::new(&data).root(),
+            };
+            let block = Block {
+                header,
+                content: Content { data },
+            };
+            self.blockchain.check_block(&block).unwrap();
+            self.blockchain.insert(&block);
+            self.mempool.remove_confirmed(&block, &self.blockchain);
+        }
+    }
+
+    fn wallet_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("htlc-{}-{}", name, std::process::id()))
+    }
+
+    fn wallet(name: &str) -> Wallet {
+        let dir = wallet_dir(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut wallet = Wallet::open(&dir, name).unwrap();
+        wallet.new_address().unwrap();
+        wallet
+    }
+
+    #[test]
+    fn atomic_swap() {
+        let alice = wallet("alice");
+        let bob = wallet("bob");
+        let alice_address = alice.addresses()[0];
+        let bob_address = bob.addresses()[0];
+        let alice_key = alice.pub_key(&alice_address).unwrap();
+        let bob_key = bob.pub_key(&bob_address).unwrap();
+        let mut chain_a = Chain::new();
+        let mut chain_b = Chain::new();
+        chain_a.mine(Some((alice_address, 10.0)));
+        chain_b.mine(Some((bob_address, 20.0)));
+
+        // alice locks 10 on chain A for bob, then bob locks 20 on chain B for alice with the
+        // same hash and a shorter timeout
+        let (preimage, hash) = generate_preimage();
+        let (htlc_a, funding) = alice
+            .create_htlc(
+                bob_key,
+                hash,
+                10,
+                10.0,
+                &chain_a.blockchain,
+                &chain_a.mempool,
+            )
+            .unwrap();
+        chain_a.submit(funding).unwrap();
+        chain_a.mine(None);
+        let (htlc_b, funding) = bob
+            .create_htlc(
+                alice_key,
+                hash,
+                6,
+                20.0,
+                &chain_b.blockchain,
+                &chain_b.mempool,
+            )
+            .unwrap();
+        chain_b.submit(funding).unwrap();
+        chain_b.mine(None);
+        assert_eq!(htlc_b.sender, bob_key);
+
+        // bob cannot claim without the preimage, nor take his funds back early
+        let guess = [0u8; 32];
+        assert!(bob
+            .claim_htlc(&htlc_a, &guess, &chain_a.blockchain)
+            .is_err());
+        let forged = htlc_a.claim(
+            &Transaction {
+                input: htlc_a.address(),
+                output: bob_address,
+                amount: 10.0,
+                lock_time: 0,
+                nonce: 0,
+            },
+            &guess,
+            &key_pair::random(),
+        );
+        assert!(chain_a.submit(forged).is_err());
+        let early_refund = bob.refund_htlc(&htlc_b, &chain_b.blockchain).unwrap();
+        assert!(chain_b.submit(early_refund).is_err());
+        // alice is not the recipient of her own contract
+        assert!(alice
+            .claim_htlc(&htlc_a, &preimage, &chain_a.blockchain)
+            .is_err());
+
+        // alice claims on chain B, revealing the preimage there
+        let claim = alice
+            .claim_htlc(&htlc_b, &preimage, &chain_b.blockchain)
+            .unwrap();
+        assert_eq!(claim.output, alice_address);
+        assert_eq!(claim.amount, 20.0);
+        chain_b.submit(claim).unwrap();
+        assert_eq!(htlc_b.find_preimage(&chain_b.blockchain), None);
+        chain_b.mine(None);
+
+        // bob reads it from chain B and claims on chain A
+        let revealed = htlc_b.find_preimage(&chain_b.blockchain).unwrap();
+        assert_eq!(htlc_a.find_preimage(&chain_a.blockchain), None);
+        let claim = bob
+            .claim_htlc(&htlc_a, &revealed, &chain_a.blockchain)
+            .unwrap();
+        chain_a.submit(claim).unwrap();
+        chain_a.mine(None);
+
+        let balance = |wallet: &Wallet, chain: &Chain| {
+            wallet.balance(&chain.blockchain, &chain.mempool).confirmed
+        };
+        assert_eq!(balance(&alice, &chain_a), 0.0);
+        assert_eq!(balance(&bob, &chain_a), 10.0);
+        assert_eq!(balance(&alice, &chain_b), 20.0);
+        assert_eq!(balance(&bob, &chain_b), 0.0);
+        // nothing is left to claim or refund
+        assert!(bob.refund_htlc(&htlc_b, &chain_b.blockchain).is_err());
+        std::fs::remove_dir_all(wallet_dir("alice")).unwrap();
+        std::fs::remove_dir_all(wallet_dir("bob")).unwrap();
+    }
+
+    #[test]
+    fn refund_after_timeout() {
+        let alice = wallet("refund");
+        let alice_address = alice.addresses()[0];
+        let bob_key = H256::from([9; 32]);
+        let mut chain = Chain::new();
+        chain.mine(Some((alice_address, 5.0)));
+        let (_, hash) = generate_preimage();
+        let (htlc, funding) = alice
+            .create_htlc(bob_key, hash, 4, 5.0, &chain.blockchain, &chain.mempool)
+            .unwrap();
+        chain.submit(funding).unwrap();
+        chain.mine(None);
+
+        // the next block is at height 3
+        let refund = alice.refund_htlc(&htlc, &chain.blockchain).unwrap();
+        assert_eq!(refund.lock_time, 4);
+        assert!(chain.submit(refund.clone()).is_err());
+        // a refund without the lock time is still rejected by the script
+        let key = key_pair::random();
+        let unlocked = Htlc {
+            sender: transaction::convertPubKeyToH256(key.public_key()),
+            ..htlc.clone()
+        };
+        let raw = Transaction {
+            input: unlocked.address(),
+            output: alice_address,
+            amount: 1.0,
+            lock_time: 0,
+            nonce: 0,
+        };
+        let context = Context::next_block(&chain.blockchain);
+        assert!(unlocked.refund(&raw, &key).validate(&context).is_err());
+        chain.mine(None);
+        let context = Context::next_block(&chain.blockchain);
+        assert!(unlocked.refund(&raw, &key).validate(&context).is_ok());
+        chain.submit(refund).unwrap();
+        chain.mine(None);
+        assert_eq!(
+            alice.balance(&chain.blockchain, &chain.mempool).confirmed,
+            5.0
+        );
+        std::fs::remove_dir_all(wallet_dir("refund")).unwrap();
+    }
+}
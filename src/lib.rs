@@ -0,0 +1,17 @@
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod block;
+pub mod blockchain;
+pub mod crypto;
+pub mod htlc;
+pub mod mempool;
+pub mod miner;
+pub mod multisig;
+pub mod network;
+pub mod script;
+pub mod transaction;
+pub mod verifier;
+pub mod wallet;
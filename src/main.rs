@@ -1,29 +1,22 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod miner;
-pub mod network;
-pub mod transaction;
-
+use bitcoin::{api, crypto, miner, network};
 use clap::clap_app;
 use crossbeam::channel;
 use log::{error, info};
-use api::Server as ApiServer;
-use network::{server, worker};
+use api::{NodeState, Server as ApiServer};
+use network::{secure, server, sync, worker};
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use crate::blockchain::Blockchain;
-use crate::block::{Header,Content,Block};
-use crate::transaction::{Transaction,SignedTransaction};
+use bitcoin::blockchain::Blockchain;
+use bitcoin::mempool::Mempool;
+use bitcoin::verifier::Verifier;
+use bitcoin::wallet::Wallet;
+use bitcoin::block::{Header,Content,Block};
+use bitcoin::transaction::{Transaction,SignedTransaction};
 
 fn main() {
     // parse command line arguments
@@ -35,6 +28,12 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg identity: --identity [FILE] "Sets the PKCS#8 file holding the node identity key, created if missing (default: identity-<p2p port>.pk8)")
+     (@arg plaintext: --("p2p-plaintext") "Disables encryption of peer connections, for local debugging")
+     (@arg wallet: --wallet [DIR] "Opens the wallet in the given directory, with the passphrase in the WALLET_PASSPHRASE environment variable")
+     (@arg wallet_restore: --("wallet-restore") [COUNT] "Restores the wallet from the seed phrase in the WALLET_MNEMONIC environment variable, deriving COUNT addresses")
+     (@arg show_mnemonic: --("show-mnemonic") "Prints the seed phrase of the wallet and exits")
+     (@arg testnet: --testnet "Runs on the test network, with its own addresses")
     )
     .get_matches();
 
@@ -42,6 +41,12 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
+    // choose the network before any address is made
+    if matches.is_present("testnet") {
+        crypto::address::set_network(crypto::address::Network::Test).unwrap();
+        info!("Running on the test network");
+    }
+
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -62,12 +67,80 @@ fn main() {
             process::exit(1);
         });
 
+    // SIGINT, SIGTERM and the API all stop the node through this channel
+    let (shutdown_tx, shutdown_rx) = channel::unbounded();
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let shutdown_tx = shutdown_tx.clone();
+        ctrlc::set_handler(move || {
+            let _ = shutdown_tx.send(());
+        })
+        .unwrap_or_else(|e| {
+            error!("Error setting signal handler: {}", e);
+            process::exit(1);
+        });
+    }
+
+    // load the identity key, which authenticates us to peers
+    let security = if matches.is_present("plaintext") {
+        info!("Peer connections are not encrypted");
+        secure::Security::Plaintext
+    } else {
+        let identity_path = match matches.value_of("identity") {
+            Some(path) => path.to_string(),
+            None => format!("identity-{}.pk8", p2p_addr.port()),
+        };
+        let identity = crypto::key_pair::load_or_generate(std::path::Path::new(&identity_path))
+            .unwrap_or_else(|e| {
+                error!("Error loading identity key {}: {}", identity_path, e);
+                process::exit(1);
+            });
+        info!("Node identity is {}", secure::peer_id(&identity));
+        secure::Security::Encrypted(Arc::new(identity))
+    };
+
+    // open the wallet, if any
+    let wallet = matches.value_of("wallet").map(|dir| {
+        let passphrase = std::env::var("WALLET_PASSPHRASE").unwrap_or_else(|_| {
+            error!("WALLET_PASSPHRASE must be set to open the wallet");
+            process::exit(1);
+        });
+        let path = std::path::Path::new(dir);
+        let wallet = match matches.value_of("wallet_restore") {
+            Some(count) => {
+                let count = count.parse::<u32>().unwrap_or_else(|e| {
+                    error!("Error parsing the number of addresses to restore: {}", e);
+                    process::exit(1);
+                });
+                let phrase = std::env::var("WALLET_MNEMONIC").unwrap_or_else(|_| {
+                    error!("WALLET_MNEMONIC must be set to restore the wallet");
+                    process::exit(1);
+                });
+                Wallet::restore(path, &passphrase, &phrase, count)
+            }
+            None => Wallet::open(path, &passphrase),
+        };
+        let wallet = wallet.unwrap_or_else(|e| {
+            error!("Error opening wallet {}: {}", dir, e);
+            process::exit(1);
+        });
+        if matches.is_present("show_mnemonic") {
+            println!("{}", wallet.mnemonic());
+            process::exit(0);
+        }
+        info!("Opened wallet {} with {} addresses", dir, wallet.addresses().len());
+        for address in wallet.addresses() {
+            info!("Wallet address {}", crypto::address::Address::new(*address));
+        }
+        Arc::new(Mutex::new(wallet))
+    });
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, security).unwrap();
+    let server_thread = server_ctx.start().unwrap();
 
     // start the worker
     let p2p_workers = matches
@@ -80,29 +153,39 @@ fn main() {
         });
     let blockchain: Blockchain = Blockchain::new();
     let wrapped_blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let verifier = Arc::new(Verifier::default());
+    let (sync_ctx, sync) = sync::new();
+    let sync_thread = sync_ctx.start();
     let worker_ctx = worker::new(
         p2p_workers,
         msg_rx,
         &server,
-        &wrapped_blockchain
+        &wrapped_blockchain,
+        &mempool,
+        &verifier,
+        &sync
     );
-    worker_ctx.start();
+    let worker_threads = worker_ctx.start();
 
     // start the miner
     
     let (miner_ctx, miner) = miner::new(
         &server,
-        &wrapped_blockchain
+        &wrapped_blockchain,
+        &mempool
     );
-    miner_ctx.start();
+    let miner_thread = miner_ctx.start();
 
     // connect to known peers
+    let mut connect_thread = None;
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
-        thread::spawn(move || {
+        let running = Arc::clone(&running);
+        connect_thread = Some(thread::spawn(move || {
             for peer in known_peers {
-                loop {
+                while running.load(Ordering::SeqCst) {
                     let addr = match peer.parse::<net::SocketAddr>() {
                         Ok(x) => x,
                         Err(e) => {
@@ -126,18 +209,41 @@ fn main() {
                     }
                 }
             }
-        });
+        }));
     }
 
 
     // start the API server
-    ApiServer::start(
+    let node = NodeState {
+        blockchain: Arc::clone(&wrapped_blockchain),
+        mempool: Arc::clone(&mempool),
+        wallet: wallet.clone(),
+        shutdown: shutdown_tx.clone(),
+        running: Arc::clone(&running),
+    };
+    let api_thread = ApiServer::start(
         api_addr,
         &miner,
         &server,
+        &node,
     );
 
-    loop {
-        std::thread::park();
+    shutdown_rx.recv().unwrap();
+    info!("Shutting down");
+    running.store(false, Ordering::SeqCst);
+    miner.exit();
+    miner_thread.join().unwrap();
+    api_thread.join().unwrap();
+    if let Some(connect_thread) = connect_thread {
+        connect_thread.join().unwrap();
+    }
+    // the workers drain their queue and stop once the server thread is gone
+    server.shutdown();
+    server_thread.join().unwrap();
+    for worker_thread in worker_threads {
+        worker_thread.join().unwrap();
     }
+    sync.stop();
+    sync_thread.join().unwrap();
+    info!("Node stopped");
 }
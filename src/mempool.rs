@@ -0,0 +1,197 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H160, H256};
+use crate::transaction::SignedTransaction;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// The maximum number of transactions kept in the mempool
+const MAX_TRANSACTIONS: usize = 50_000;
+
+/// Transactions waiting to be included in a block, shared by the miner and the worker threads
+#[derive(Default)]
+pub struct Mempool {
+    transactions: HashMap<H256, SignedTransaction>,
+    /// Transaction hashes in arrival order, oldest first. May hold hashes already removed.
+    order: VecDeque<H256>,
+    /// The hashes of the transactions sent by each account, by nonce
+    pending: HashMap<H160, BTreeMap<u64, H256>>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.transactions.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Iterate over the transactions with their hashes, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&H256, &SignedTransaction)> {
+        self.transactions.iter()
+    }
+
+    /// Add a transaction. Returns false if it or another transaction of its account with its
+    /// nonce was already in the mempool. The oldest transaction is dropped when the mempool is
+    /// full.
+    pub fn insert(&mut self, transaction: SignedTransaction) -> bool {
+        let hash = transaction.hash();
+        if self.transactions.contains_key(&hash) {
+            return false;
+        }
+        if let Some(pending) = self.pending.get(&transaction.input) {
+            if pending.contains_key(&transaction.nonce) {
+                return false;
+            }
+        }
+        while self.transactions.len() >= MAX_TRANSACTIONS {
+            match self.order.pop_front() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        self.pending
+            .entry(transaction.input)
+            .or_default()
+            .insert(transaction.nonce, hash);
+        self.transactions.insert(hash, transaction);
+        self.order.push_back(hash);
+        true
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some(transaction) = self.transactions.remove(hash) {
+            let pending = self.pending.get_mut(&transaction.input).unwrap();
+            pending.remove(&transaction.nonce);
+            if pending.is_empty() {
+                self.pending.remove(&transaction.input);
+            }
+        }
+    }
+
+    /// The nonce of the next transaction of an account, given the next nonce after the chain: the
+    /// first one from there that no transaction in the mempool has. A gap left by an evicted
+    /// transaction is filled again rather than leaving the later ones stuck.
+    pub fn next_nonce(&self, account: &H160, confirmed: u64) -> u64 {
+        let mut nonce = confirmed;
+        if let Some(pending) = self.pending.get(account) {
+            for next in pending.range(confirmed..).map(|(x, _)| *x) {
+                if next != nonce {
+                    break;
+                }
+                nonce += 1;
+            }
+        }
+        nonce
+    }
+
+    /// Up to `max` transactions in arrival order, oldest first.
+    pub fn oldest(&self, max: usize) -> Vec<SignedTransaction> {
+        self.order
+            .iter()
+            .filter_map(|hash| self.transactions.get(hash))
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the transactions confirmed by a block just added to `blockchain`, and the ones
+    /// whose nonce the longest chain has used by now, which can never be included.
+    pub fn remove_confirmed(&mut self, block: &Block, blockchain: &Blockchain) {
+        for transaction in block.content.data.iter() {
+            self.remove(&transaction.hash());
+        }
+        let accounts: HashSet<H160> = self.pending.keys().cloned().collect();
+        let nonces = blockchain.next_nonces(&blockchain.tip(), accounts);
+        let stale: Vec<H256> = self
+            .pending
+            .iter()
+            .flat_map(|(account, pending)| pending.range(..nonces[account]).map(|(_, x)| *x))
+            .collect();
+        for hash in stale.iter() {
+            self.remove(hash);
+        }
+        if self.order.len() > 2 * self.transactions.len() + 64 {
+            let transactions = &self.transactions;
+            self.order.retain(|hash| transactions.contains_key(hash));
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::transaction::generate_random_signed_transaction;
+
+    #[test]
+    fn insert_and_confirm() {
+        let mut mempool = Mempool::new();
+        let (_, first, _) = generate_random_signed_transaction();
+        let (_, second, _) = generate_random_signed_transaction();
+        assert!(mempool.insert(first.clone()));
+        assert!(!mempool.insert(first.clone()));
+        assert!(mempool.insert(second.clone()));
+        let oldest: Vec<H256> = mempool.oldest(10).iter().map(|x| x.hash()).collect();
+        assert_eq!(oldest, vec![first.hash(), second.hash()]);
+
+        let mut block = generate_random_block(&H256::from([0; 32]));
+        block.content.data.push(first.clone());
+        mempool.remove_confirmed(&block, &Blockchain::new());
+        assert!(!mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+        assert_eq!(mempool.oldest(10).len(), 1);
+        assert_eq!(mempool.next_nonce(&first.input, 0), 0);
+        assert_eq!(mempool.next_nonce(&second.input, 0), 1);
+    }
+
+    #[test]
+    fn nonces() {
+        let mut mempool = Mempool::new();
+        let (_, first, _) = generate_random_signed_transaction();
+        let with_nonce = |nonce: u64, amount: f32| SignedTransaction {
+            nonce,
+            amount,
+            ..first.clone()
+        };
+        let account = first.input;
+        for nonce in 0..3 {
+            assert!(mempool.insert(with_nonce(nonce, 1.0)));
+        }
+        assert_eq!(mempool.next_nonce(&account, 0), 3);
+        // another transaction with a taken nonce
+        assert!(!mempool.insert(with_nonce(1, 2.0)));
+
+        // a gap, as left by eviction, is filled again
+        mempool.remove(&with_nonce(1, 1.0).hash());
+        assert_eq!(mempool.next_nonce(&account, 0), 1);
+        assert!(mempool.insert(with_nonce(1, 2.0)));
+        assert_eq!(mempool.next_nonce(&account, 0), 3);
+
+        // another transaction with nonce 0 is confirmed, so ours can never be
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.data.push(with_nonce(0, 3.0));
+        blockchain.insert(&block);
+        mempool.remove_confirmed(&block, &blockchain);
+        assert!(!mempool.contains(&with_nonce(0, 1.0).hash()));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(
+            mempool.next_nonce(&account, blockchain.next_nonce(&account)),
+            3
+        );
+    }
+}
@@ -1,5 +1,4 @@
 use crate::network::server::Handle as ServerHandle;
-use crate::network::message::Message;
 use log::info;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
@@ -10,15 +9,20 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::script::Context as ScriptContext;
 use crate::block::{Header,Content,Block};
 use crate::transaction::{Transaction,SignedTransaction};
 use crate::transaction;
-use crate::crypto::merkle::{MerkleNode,MerkleTree};
+use crate::crypto::merkle::MerkleTree;
 use crate::crypto::hash::{H256, Hashable};
 use rand::Rng;
 use crate::crypto::key_pair;
 
 
+/// The maximum number of mempool transactions put in a block
+const MAX_BLOCK_TRANSACTIONS: usize = 256;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
@@ -35,7 +39,8 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>
 }
 
 #[derive(Clone)]
@@ -46,7 +51,8 @@ pub struct Handle {
 
 pub fn new(
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -54,7 +60,8 @@ pub fn new(
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
-        blockchain: Arc::clone(blockchain)
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool)
     };
 
     let handle = Handle {
@@ -78,14 +85,15 @@ impl Handle {
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
@@ -139,29 +147,54 @@ impl Context {
             // let sign1: H256 = sig1.into();
             // let sign2: H256 = sig2.into();
             // let signature: [H256;2] = [sign1,sign2];
-            let (_,faltu_transaction1,_) = transaction::generate_random_signed_transaction();
-            let (_,faltu_transaction2,_) = transaction::generate_random_signed_transaction();
-            let (_,faltu_transaction3,_) = transaction::generate_random_signed_transaction();
-            let mut data: Vec<SignedTransaction> = Vec::new();
-            data.push(faltu_transaction1.clone());
-            data.push(faltu_transaction2.clone());
-            data.push(faltu_transaction3.clone());
+            let (context, medianTime) = {
+                let blockchain = self.blockchain.lock().unwrap();
+                (ScriptContext::next_block(&blockchain), blockchain.median_time_past(&parent))
+            };
+            let mut data: Vec<SignedTransaction> = self.mempool.lock().unwrap().oldest(MAX_BLOCK_TRANSACTIONS);
+            data.retain(|x| x.is_final(&context));
+            // keep the transactions of each account in nonce order, without gaps
+            let mut nonces = self.blockchain.lock().unwrap().next_nonces(&parent, data.iter().map(|x| x.input).collect());
+            data.retain(|x| {
+                let nonce = nonces.get_mut(&x.input).unwrap();
+                let next = x.nonce == *nonce;
+                if next {
+                    *nonce += 1;
+                }
+                next
+            });
+            if data.is_empty() {
+                let (_,faltu_transaction1,_) = transaction::generate_random_signed_transaction();
+                let (_,faltu_transaction2,_) = transaction::generate_random_signed_transaction();
+                let (_,faltu_transaction3,_) = transaction::generate_random_signed_transaction();
+                data.push(faltu_transaction1.clone());
+                data.push(faltu_transaction2.clone());
+                data.push(faltu_transaction3.clone());
+            }
 
             let content = Content{data: data.clone()};
 
             let merkle_tree : MerkleTree = MerkleTree::new(&data);
-            let timestamp: u128 = SystemTime::now().duration_since(UNIX_EPOCH).expect("dafuq").as_millis();
+            let timestamp: u128 = SystemTime::now().duration_since(UNIX_EPOCH).expect("dafuq").as_millis().max(medianTime + 1);
             let header = Header{parent : parent, nonce: nonce, difficulty: difficulty,timestamp: timestamp,merkle_root: merkle_tree.root()};
 
             let block = Block{header: header, content: content};
 
-            if block.hash() <= difficulty && parent == self.blockchain.lock().unwrap().tip(){
-                self.blockchain.lock().unwrap().insert(&block);
+            let inserted = {
+                let mut blockchain = self.blockchain.lock().unwrap();
+                let inserted = block.hash() <= difficulty && parent == blockchain.tip();
+                if inserted {
+                    blockchain.insert(&block);
+                    self.mempool.lock().unwrap().remove_confirmed(&block, &blockchain);
+                }
+                inserted
+            };
+            if inserted {
                 blocks_mined = blocks_mined + 1;
                 println!("Blocks mined {:?}         Chain length {:?}", blocks_mined, self.blockchain.lock().unwrap().chainLength );
                 let mut blockHashVec: Vec<H256> = Vec::new();//self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
                 blockHashVec.push(block.hash());
-                self.server.broadcast(Message::NewBlockHashes(blockHashVec));
+                self.server.announce_blocks(blockHashVec);
             }
 
             if let OperatingState::Run(i) = self.operating_state {
@@ -0,0 +1,187 @@
+//! M-of-N multi-signature addresses.
+//!
+//! A multisig address is derived from a threshold and a sorted set of Ed25519 public keys, so
+//! the same keys and threshold always give the same address. A transaction spending from it
+//! carries every key and at least `threshold` signatures, each tagged with the index of its key.
+
+use crate::crypto::hash::{H160, H256};
+use crate::transaction::{self, Transaction};
+use ring::signature::{Ed25519KeyPair, EdDSAParameters, KeyPair, VerificationAlgorithm};
+use serde::{Deserialize, Serialize};
+use untrusted::Input;
+
+use std::convert::TryInto;
+
+/// The largest number of keys of a multisig address
+pub const MAX_KEYS: usize = 16;
+
+/// The keys of a multisig address with the signatures gathered so far
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Multisig {
+    /// The number of signatures required
+    pub threshold: u8,
+    /// Every key of the address, in ascending order
+    pub pub_keys: Vec<H256>,
+    /// Signatures with the index of their key in `pub_keys`, in ascending order of index
+    pub signatures: Vec<(u8, [H256; 2])>,
+}
+
+impl Multisig {
+    /// A multisig address requiring `threshold` of the given keys, in any order
+    pub fn new(threshold: u8, pub_keys: &[H256]) -> Result<Self, String> {
+        let mut pub_keys = pub_keys.to_vec();
+        pub_keys.sort();
+        let multisig = Multisig {
+            threshold,
+            pub_keys,
+            signatures: Vec::new(),
+        };
+        multisig.check_keys()?;
+        Ok(multisig)
+    }
+
+    fn check_keys(&self) -> Result<(), String> {
+        if self.pub_keys.is_empty() || self.pub_keys.len() > MAX_KEYS {
+            return Err(format!(
+                "a multisig address has 1 to {} keys, not {}",
+                MAX_KEYS,
+                self.pub_keys.len()
+            ));
+        }
+        if self.threshold == 0 || self.threshold as usize > self.pub_keys.len() {
+            return Err(format!(
+                "invalid threshold {} of {}",
+                self.threshold,
+                self.pub_keys.len()
+            ));
+        }
+        if self.pub_keys.windows(2).any(|x| x[0] >= x[1]) {
+            return Err("keys must be distinct and sorted".to_string());
+        }
+        Ok(())
+    }
+
+    /// The address of the keys and threshold
+    pub fn address(&self) -> H160 {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(b"multisig");
+        ctx.update(&[self.threshold, self.pub_keys.len() as u8]);
+        for pub_key in self.pub_keys.iter() {
+            ctx.update(pub_key.as_ref());
+        }
+        let hash: [u8; 20] = ctx.finish().as_ref()[12..].try_into().unwrap();
+        hash.into()
+    }
+
+    /// Add the signature of one of the keys to a transaction.
+    pub fn sign(&mut self, raw: &Transaction, key: &Ed25519KeyPair) -> Result<(), String> {
+        let pub_key = transaction::convertPubKeyToH256(key.public_key());
+        let index =
+            self.pub_keys
+                .iter()
+                .position(|x| *x == pub_key)
+                .ok_or_else(|| "the key is not part of the address".to_string())? as u8;
+        match self.signatures.binary_search_by_key(&index, |x| x.0) {
+            Ok(_) => Err("the key has signed already".to_string()),
+            Err(position) => {
+                let signature = transaction::convertSigToH256(&transaction::sign(raw, key));
+                self.signatures.insert(position, (index, signature));
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that at least `threshold` distinct keys signed the message.
+    pub fn verify(&self, message: &[u8]) -> Result<(), String> {
+        self.check_keys()?;
+        if self.signatures.windows(2).any(|x| x[0].0 >= x[1].0) {
+            return Err("signatures must be by distinct keys, in key order".to_string());
+        }
+        if self.signatures.len() < self.threshold as usize {
+            return Err(format!(
+                "{} signatures of the {} required",
+                self.signatures.len(),
+                self.threshold
+            ));
+        }
+        for (index, signature) in self.signatures.iter() {
+            let pub_key = self
+                .pub_keys
+                .get(*index as usize)
+                .ok_or_else(|| format!("no key at index {}", index))?;
+            let sig = transaction::convertH256ToSigRef(*signature);
+            VerificationAlgorithm::verify(
+                &EdDSAParameters,
+                Input::from(pub_key.as_ref()),
+                Input::from(message),
+                Input::from(&sig),
+            )
+            .map_err(|_| format!("invalid signature by key {}", index))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::script::Context;
+    use crate::transaction::SignedTransaction;
+
+    const CONTEXT: Context = Context { height: 1, time: 0 };
+
+    #[test]
+    fn two_of_three() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let pub_keys: Vec<H256> = keys
+            .iter()
+            .map(|x| transaction::convertPubKeyToH256(x.public_key()))
+            .collect();
+        let mut multisig = Multisig::new(2, &pub_keys).unwrap();
+        let mut reversed = pub_keys.clone();
+        reversed.reverse();
+        assert_eq!(
+            Multisig::new(2, &reversed).unwrap().address(),
+            multisig.address()
+        );
+        assert_ne!(
+            Multisig::new(1, &pub_keys).unwrap().address(),
+            multisig.address()
+        );
+        assert!(Multisig::new(4, &pub_keys).is_err());
+        assert!(Multisig::new(1, &[pub_keys[0], pub_keys[0]]).is_err());
+
+        let raw = Transaction {
+            input: multisig.address(),
+            output: H160::from([1; 20]),
+            amount: 3.0,
+            lock_time: 0,
+            nonce: 0,
+        };
+        multisig.sign(&raw, &keys[2]).unwrap();
+        assert!(multisig.sign(&raw, &keys[2]).is_err());
+        assert!(multisig.sign(&raw, &key_pair::random()).is_err());
+        let one_signature = SignedTransaction::from_multisig(&raw, multisig.clone());
+        assert!(one_signature.validate(&CONTEXT).is_err());
+
+        multisig.sign(&raw, &keys[0]).unwrap();
+        let signed = SignedTransaction::from_multisig(&raw, multisig.clone());
+        assert!(signed.validate(&CONTEXT).is_ok());
+
+        // the same signer counted twice
+        let mut duplicated = multisig.clone();
+        duplicated.signatures[1] = duplicated.signatures[0];
+        assert!(SignedTransaction::from_multisig(&raw, duplicated)
+            .validate(&CONTEXT)
+            .is_err());
+        // the keys of another address
+        let mut other = signed.clone();
+        other.input = H160::from([2; 20]);
+        assert!(other.validate(&CONTEXT).is_err());
+        // a single-key signature alongside is rejected
+        let mut mixed = signed;
+        mixed.pub_key = pub_keys[0];
+        assert!(mixed.validate(&CONTEXT).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Compact block relay.
+//!
+//! A compact block is a header with a short ID per transaction. The receiver fills in the
+//! transactions it already has in its mempool, asks the sender for the rest by index, and checks
+//! the result against the Merkle root. Short IDs are keyed with the block hash, so a collision
+//! crafted against one block does not carry over to others. Only the peer asked for the missing
+//! transactions may send them, and a block waits for them for a limited time.
+
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::merkle::MerkleTree;
+use crate::mempool::Mempool;
+use crate::transaction::SignedTransaction;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The maximum number of compact blocks waiting for missing transactions
+const MAX_PENDING_BLOCKS: usize = 32;
+/// How long a peer has to send the missing transactions of a compact block
+const PENDING_BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A block announced by its header and the short IDs of its transactions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub short_ids: Vec<u64>,
+}
+
+/// The short ID of a transaction within the block with the given hash
+pub fn short_id(block_hash: &H256, transaction_hash: &H256) -> u64 {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(block_hash.as_ref());
+    ctx.update(transaction_hash.as_ref());
+    u64::from_le_bytes(ctx.finish().as_ref()[..8].try_into().unwrap())
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        CompactBlock {
+            header: block.header.clone(),
+            short_ids: block
+                .content
+                .data
+                .iter()
+                .map(|x| short_id(&hash, &x.hash()))
+                .collect(),
+        }
+    }
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+/// A compact block being reconstructed
+pub struct PartialBlock {
+    header: Header,
+    short_ids: Vec<u64>,
+    transactions: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Fill in the transactions found in the mempool. Fails if the compact block repeats a short
+    /// ID, in which case the full block has to be fetched.
+    pub fn new(compact: CompactBlock, mempool: &Mempool) -> Result<Self, String> {
+        let hash = compact.header.hash();
+        let mut seen = HashSet::new();
+        if !compact.short_ids.iter().all(|x| seen.insert(*x)) {
+            return Err("duplicate short ID".to_string());
+        }
+        // mempool transactions by short ID, None if two of them share one
+        let mut candidates: HashMap<u64, Option<&SignedTransaction>> = HashMap::new();
+        for (transaction_hash, transaction) in mempool.iter() {
+            let id = short_id(&hash, transaction_hash);
+            if !seen.contains(&id) {
+                continue;
+            }
+            match candidates.entry(id) {
+                Entry::Occupied(mut entry) => {
+                    entry.insert(None);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(Some(transaction));
+                }
+            }
+        }
+        let transactions = compact
+            .short_ids
+            .iter()
+            .map(|id| candidates.get(id).cloned().flatten().cloned())
+            .collect();
+        Ok(PartialBlock {
+            header: compact.header,
+            short_ids: compact.short_ids,
+            transactions,
+        })
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+
+    /// The indexes of the transactions we still need
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Fill in the missing transactions sent by the peer, in the order of `missing()`.
+    pub fn fill(&mut self, transactions: Vec<SignedTransaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(format!(
+                "expected {} transactions, got {}",
+                missing.len(),
+                transactions.len()
+            ));
+        }
+        let hash = self.hash();
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            let index = index as usize;
+            if short_id(&hash, &transaction.hash()) != self.short_ids[index] {
+                return Err(format!("transaction {} does not match its short ID", index));
+            }
+            self.transactions[index] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// The full block, once every transaction is known and they match the Merkle root.
+    pub fn into_block(self) -> Result<Block, String> {
+        let data: Option<Vec<SignedTransaction>> = self.transactions.into_iter().collect();
+        let data = data.ok_or_else(|| "transactions are missing".to_string())?;
+        if data.is_empty() || MerkleTree::new(&data).root() != self.header.merkle_root {
+            return Err("Merkle root mismatch".to_string());
+        }
+        Ok(Block {
+            header: self.header,
+            content: Content { data },
+        })
+    }
+}
+
+struct Pending {
+    partial: PartialBlock,
+    /// The peer we asked for the missing transactions
+    peer: SocketAddr,
+    requested_at: Instant,
+}
+
+/// Compact blocks waiting for the transactions we asked a peer for, shared by all worker threads
+#[derive(Default)]
+pub struct PendingBlocks {
+    blocks: HashMap<H256, Pending>,
+}
+
+impl PendingBlocks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Keep a block until `peer` sends its missing transactions. A block asked from another
+    /// peer before now waits for `peer` instead. Returns false if too many blocks are waiting.
+    pub fn insert(&mut self, partial: PartialBlock, peer: SocketAddr) -> bool {
+        self.expire();
+        if self.blocks.len() >= MAX_PENDING_BLOCKS && !self.blocks.contains_key(&partial.hash()) {
+            return false;
+        }
+        let pending = Pending {
+            partial,
+            peer,
+            requested_at: Instant::now(),
+        };
+        self.blocks.insert(pending.partial.hash(), pending);
+        true
+    }
+
+    /// Take the block waiting for transactions from `peer`. A block asked from another peer
+    /// keeps waiting.
+    pub fn take(&mut self, hash: &H256, peer: SocketAddr) -> Option<PartialBlock> {
+        self.expire();
+        match self.blocks.get(hash) {
+            Some(pending) if pending.peer == peer => self.blocks.remove(hash).map(|x| x.partial),
+            _ => None,
+        }
+    }
+
+    /// Forget the blocks whose peer did not answer in time.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.blocks.retain(|hash, pending| {
+            let waiting = now.duration_since(pending.requested_at) <= PENDING_BLOCK_TIMEOUT;
+            if !waiting {
+                debug!(
+                    "peer {} did not send the transactions of block {}",
+                    pending.peer, hash
+                );
+            }
+            waiting
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::transaction::generate_random_signed_transaction;
+
+    #[test]
+    fn reconstruct() {
+        let mut block = generate_random_block(&H256::from([0; 32]));
+        let mut mempool = Mempool::new();
+        for i in 0..4 {
+            let (_, transaction, _) = generate_random_signed_transaction();
+            // the receiver has every other transaction
+            if i % 2 == 0 {
+                mempool.insert(transaction.clone());
+            }
+            block.content.data.push(transaction);
+        }
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.hash(), block.hash());
+
+        let mut partial = PartialBlock::new(compact.clone(), &mempool).unwrap();
+        let missing = partial.missing();
+        assert_eq!(missing, vec![0, 2, 4]);
+        let transactions: Vec<SignedTransaction> = missing
+            .iter()
+            .map(|i| block.content.data[*i as usize].clone())
+            .collect();
+
+        // the wrong transactions are rejected
+        let mut wrong = transactions.clone();
+        wrong.swap(0, 1);
+        assert!(PartialBlock::new(compact.clone(), &mempool)
+            .unwrap()
+            .fill(wrong)
+            .is_err());
+
+        partial.fill(transactions).unwrap();
+        let rebuilt = partial.into_block().unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(
+            bincode::serialize(&rebuilt).unwrap(),
+            bincode::serialize(&block).unwrap()
+        );
+
+        // a tampered transaction list does not match the header
+        let mut forged = compact;
+        forged.short_ids.swap(1, 3);
+        let mut partial = PartialBlock::new(forged, &mempool).unwrap();
+        let transactions = partial
+            .missing()
+            .iter()
+            .map(|i| block.content.data[*i as usize].clone())
+            .collect();
+        partial.fill(transactions).unwrap();
+        assert!(partial.into_block().is_err());
+    }
+
+    #[test]
+    fn pending_blocks() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mempool = Mempool::new();
+        let partial =
+            |block: &Block| PartialBlock::new(CompactBlock::new(block), &mempool).unwrap();
+        let mut pending = PendingBlocks::new();
+        let block = generate_random_block(&H256::from([0; 32]));
+        let hash = block.hash();
+        assert!(pending.insert(partial(&block), peer));
+        // only the peer we asked may answer
+        assert!(pending.take(&hash, other).is_none());
+        assert!(pending.blocks.contains_key(&hash));
+        assert_eq!(pending.take(&hash, peer).unwrap().hash(), hash);
+        assert!(pending.blocks.is_empty());
+
+        // a full set of blocks still lets a waiting block be asked from another peer
+        assert!(pending.insert(partial(&block), peer));
+        for _ in 1..MAX_PENDING_BLOCKS {
+            assert!(pending.insert(partial(&generate_random_block(&hash)), peer));
+        }
+        assert!(!pending.insert(partial(&generate_random_block(&hash)), peer));
+        assert!(pending.insert(partial(&block), other));
+        assert!(pending.take(&hash, peer).is_none());
+        assert!(pending.take(&hash, other).is_some());
+
+        // and makes room as blocks expire
+        assert!(pending.insert(partial(&block), peer));
+        pending.blocks.get_mut(&hash).unwrap().requested_at -= PENDING_BLOCK_TIMEOUT * 2;
+        assert!(pending.insert(partial(&generate_random_block(&hash)), peer));
+        assert!(pending.take(&hash, peer).is_none());
+        assert_eq!(pending.blocks.len(), MAX_PENDING_BLOCKS);
+    }
+}
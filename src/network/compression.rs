@@ -0,0 +1,88 @@
+//! Payload compression of peer messages.
+//!
+//! Every message starts with one byte naming the codec of the rest. Both sides list the codecs
+//! they support in their `Hello`, and a sender only compresses once the peer has listed the
+//! codec, and only messages above a size threshold that actually shrink.
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+
+/// Messages smaller than this are sent uncompressed
+const COMPRESSION_THRESHOLD: usize = 512;
+/// The largest message we agree to decompress, to bound the memory a peer can make us use
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Lz4 = 1,
+}
+
+/// The codecs this node supports, most preferred first
+pub const SUPPORTED: &[Compression] = &[Compression::Lz4];
+
+/// The codec to use with a peer supporting the given codecs
+pub fn negotiate(theirs: &[Compression]) -> Compression {
+    SUPPORTED
+        .iter()
+        .find(|x| theirs.contains(x))
+        .cloned()
+        .unwrap_or(Compression::None)
+}
+
+/// Prefix a serialized message with its codec, compressing it if worthwhile.
+pub fn encode(payload: &[u8], codec: Compression) -> Vec<u8> {
+    if codec == Compression::Lz4 && payload.len() >= COMPRESSION_THRESHOLD {
+        let compressed = lz4_flex::compress_prepend_size(payload);
+        if compressed.len() < payload.len() {
+            return [&[Compression::Lz4 as u8][..], &compressed].concat();
+        }
+    }
+    [&[Compression::None as u8][..], payload].concat()
+}
+
+/// Undo `encode`, returning the serialized message.
+pub fn decode(frame: &[u8]) -> Result<Vec<u8>, String> {
+    match frame.split_first() {
+        Some((&tag, payload)) if tag == Compression::None as u8 => Ok(payload.to_vec()),
+        Some((&tag, payload)) if tag == Compression::Lz4 as u8 => {
+            if payload.len() < 4 {
+                return Err("truncated compressed message".to_string());
+            }
+            let size = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+            if size > MAX_DECOMPRESSED_SIZE {
+                return Err(format!("compressed message too large: {} bytes", size));
+            }
+            lz4_flex::decompress_size_prepended(payload).map_err(|e| e.to_string())
+        }
+        Some((tag, _)) => Err(format!("unknown compression {}", tag)),
+        None => Err("empty message".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let small = b"ping".to_vec();
+        let large: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        for codec in [Compression::None, Compression::Lz4].iter() {
+            assert_eq!(decode(&encode(&small, *codec)).unwrap(), small);
+            assert_eq!(decode(&encode(&large, *codec)).unwrap(), large);
+        }
+        assert_eq!(encode(&small, Compression::Lz4)[0], Compression::None as u8);
+        let compressed = encode(&large, Compression::Lz4);
+        assert_eq!(compressed[0], Compression::Lz4 as u8);
+        assert!(compressed.len() < large.len() / 4);
+
+        assert_eq!(negotiate(&[Compression::Lz4]), Compression::Lz4);
+        assert_eq!(negotiate(&[]), Compression::None);
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[9, 1, 2]).is_err());
+        // a claimed size above the limit is rejected before decompressing
+        assert!(decode(&[1, 0xff, 0xff, 0xff, 0xff, 0]).is_err());
+    }
+}
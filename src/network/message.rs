@@ -2,15 +2,48 @@ use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
 use crate::block::{Header,Content,Block};
 use crate::transaction::{SignedTransaction};
+use super::compact::CompactBlock;
+use super::compression::Compression;
+
+/// The version of the protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The most blocks a `GetBlock` or `GetCompactBlock` may ask for
+pub const MAX_BLOCKS_PER_REQUEST: usize = 128;
+/// The most transactions a `GetTransaction` may ask for
+pub const MAX_TRANSACTIONS_PER_REQUEST: usize = 1000;
+/// The most hashes a `GetHeaders` locator may hold
+pub const MAX_LOCATOR_LENGTH: usize = 101;
+
+/// The first message each side sends after connecting
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub version: u32,
+    pub user_agent: String,
+    /// The address the sender's P2P server listens at
+    pub listen_addr: std::net::SocketAddr,
+    /// The compression codecs the sender can decode
+    pub compression: Vec<Compression>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-    Ping(String),
-    Pong(String),
+    Hello(Hello),
+    /// Sent before closing the connection, with the reason
+    Goodbye(String),
+    Ping(u64),
+    Pong(u64),
     NewBlockHashes(Vec<H256>),
     GetBlock(Vec<H256>),
     Block(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransaction(Vec<H256>),
-    Transaction(Vec<SignedTransaction>)
+    Transaction(Vec<SignedTransaction>),
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// Ask for blocks in compact form
+    GetCompactBlock(Vec<H256>),
+    CompactBlock(Vec<CompactBlock>),
+    /// Ask for the transactions at these indexes of a block
+    GetBlockTransactions(H256, Vec<u32>),
+    BlockTransactions(H256, Vec<SignedTransaction>),
 }
@@ -1,4 +1,13 @@
+pub mod compact;
+pub mod compression;
 pub mod message;
+pub mod orphan;
 pub mod peer;
+pub mod ratelimit;
+pub mod request;
+pub mod secure;
 pub mod server;
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod simulator;
+pub mod sync;
 pub mod worker;
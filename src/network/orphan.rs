@@ -0,0 +1,220 @@
+use crate::block::Block;
+use crate::crypto::hash::{Hashable, H256};
+use log::debug;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The maximum number of orphan blocks kept in the pool
+const MAX_ORPHANS: usize = 750;
+/// The maximum number of orphan blocks one peer may have in the pool
+const MAX_ORPHANS_PER_PEER: usize = 100;
+/// How long an orphan block waits for its parent before it is dropped
+const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+/// How long we wait for a missing parent we asked for before asking again
+const PARENT_REQUEST_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Orphan {
+    block: Block,
+    peer: SocketAddr,
+    received_at: Instant,
+}
+
+/// Blocks whose parent we do not have yet, shared by all worker threads
+#[derive(Default)]
+pub struct OrphanPool {
+    /// Orphan blocks by their own hash
+    blocks: HashMap<H256, Orphan>,
+    /// Parent hash -> hashes of the orphan blocks waiting for it
+    children: HashMap<H256, Vec<H256>>,
+    /// Number of orphan blocks each peer sent us
+    per_peer: HashMap<SocketAddr, usize>,
+    /// Missing parents we asked for, and when
+    requested: HashMap<H256, Instant>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Add an orphan block received from `peer`. Returns false if the block was already in the
+    /// pool or the peer has used up its quota.
+    pub fn insert(&mut self, block: Block, peer: SocketAddr) -> bool {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return false;
+        }
+        self.expire();
+        if self.per_peer.get(&peer).cloned().unwrap_or(0) >= MAX_ORPHANS_PER_PEER {
+            debug!("Peer {} exceeded its orphan block quota", peer);
+            return false;
+        }
+        if self.blocks.len() >= MAX_ORPHANS {
+            let oldest = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, x)| x.received_at)
+                .map(|(hash, _)| *hash)
+                .unwrap();
+            self.remove(&oldest);
+        }
+        self.children
+            .entry(block.header.parent)
+            .or_default()
+            .push(hash);
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        self.blocks.insert(
+            hash,
+            Orphan {
+                block,
+                peer,
+                received_at: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Whether to ask for the missing parent of an orphan block, recording the request if so. A
+    /// parent that is an orphan itself is not asked for, since its own parent was, and a parent is
+    /// asked for again only after `PARENT_REQUEST_INTERVAL`.
+    pub fn request_parent(&mut self, parent: &H256) -> bool {
+        if self.blocks.contains_key(parent) {
+            return false;
+        }
+        let now = Instant::now();
+        match self.requested.get(parent) {
+            Some(at) if now.duration_since(*at) < PARENT_REQUEST_INTERVAL => false,
+            _ => {
+                self.requested.insert(*parent, now);
+                true
+            }
+        }
+    }
+
+    /// Remove and return every orphan block whose parent is `parent`.
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = match self.children.get(parent) {
+            Some(hashes) => hashes.clone(),
+            None => return Vec::new(),
+        };
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let orphan = self.blocks.remove(hash)?;
+        let parent = orphan.block.header.parent;
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|x| x != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+                self.requested.remove(&parent);
+            }
+        }
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        Some(orphan.block)
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<H256> = self
+            .blocks
+            .iter()
+            .filter(|(_, x)| now.duration_since(x.received_at) > ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn siblings_and_descendants() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut pool = OrphanPool::new();
+        let parent = generate_random_hash();
+        let child1 = generate_random_block(&parent);
+        let child2 = generate_random_block(&parent);
+        let grandchild = generate_random_block(&child1.hash());
+        assert!(pool.insert(child1.clone(), peer));
+        assert!(pool.insert(child2.clone(), peer));
+        assert!(pool.insert(grandchild.clone(), peer));
+        assert!(!pool.insert(child1.clone(), peer));
+        assert_eq!(pool.blocks.len(), 3);
+
+        let children = pool.take_children(&parent);
+        assert_eq!(children.len(), 2);
+        assert!(children.iter().any(|x| x.hash() == child2.hash()));
+        let grandchildren = pool.take_children(&child1.hash());
+        assert_eq!(grandchildren.len(), 1);
+        assert_eq!(grandchildren[0].hash(), grandchild.hash());
+        assert!(pool.blocks.is_empty());
+        assert!(pool.per_peer.is_empty());
+    }
+
+    #[test]
+    fn parent_requests() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut pool = OrphanPool::new();
+        let parent = generate_random_hash();
+        let child1 = generate_random_block(&parent);
+        let child2 = generate_random_block(&parent);
+        let grandchild = generate_random_block(&child1.hash());
+        assert!(pool.insert(child1.clone(), peer));
+        assert!(pool.request_parent(&parent));
+        // a sibling waits for the same parent, and a grandchild for an orphan
+        assert!(pool.insert(child2, peer));
+        assert!(!pool.request_parent(&parent));
+        assert!(pool.insert(grandchild, peer));
+        assert!(!pool.request_parent(&child1.hash()));
+
+        // asked again once the request is old
+        *pool.requested.get_mut(&parent).unwrap() -= PARENT_REQUEST_INTERVAL;
+        assert!(pool.request_parent(&parent));
+        pool.take_children(&parent);
+        assert!(pool.requested.is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut pool = OrphanPool::new();
+        let first = generate_random_block(&generate_random_hash());
+        assert!(pool.insert(first.clone(), peer));
+        std::thread::sleep(Duration::from_millis(1));
+        for _ in 1..MAX_ORPHANS_PER_PEER {
+            assert!(pool.insert(generate_random_block(&generate_random_hash()), peer));
+        }
+        // the peer used up its quota
+        assert!(!pool.insert(generate_random_block(&generate_random_hash()), peer));
+
+        // other peers fill the pool, evicting the oldest block
+        let mut port: u16 = 7000;
+        while pool.blocks.len() < MAX_ORPHANS || pool.contains(&first.hash()) {
+            let other = SocketAddr::new(peer.ip(), port);
+            for _ in 0..MAX_ORPHANS_PER_PEER {
+                pool.insert(generate_random_block(&generate_random_hash()), other);
+            }
+            port += 1;
+        }
+        assert_eq!(pool.blocks.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&first.hash()));
+    }
+}
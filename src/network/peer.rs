@@ -1,10 +1,33 @@
+use super::compression::{self, Compression};
 use super::message;
-use log::{trace, warn};
+use super::ratelimit::{RateLimiter, Rejection};
+use super::request::{Reply, RequestError, Requests};
+use super::secure::{self, Security};
+use crate::crypto::hash::H256;
+use log::{info, trace, warn};
 use mio;
 use mio_extras::channel;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The maximum number of block and transaction hashes remembered per peer
+const MAX_KNOWN_INVENTORY: usize = 10000;
+/// How often a peer is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a peer has to answer a ping before it is disconnected
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a new peer has to send its `Hello` before it is disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many bytes may wait in a peer's outgoing queue before further messages are dropped
+const MAX_QUEUED_BYTES: usize = 16 * 1024 * 1024;
+/// The largest frame a peer may send us
+const MAX_MESSAGE_LENGTH: usize = 32 * 1024 * 1024;
 
 enum DecodeState {
     Length,
@@ -14,6 +37,8 @@ enum DecodeState {
 pub enum ReadResult {
     Continue,
     Message(Vec<u8>),
+    /// The peer's handshake frame, which must be passed to `Context::complete_handshake`
+    Handshake(Vec<u8>),
     EOF,
 }
 
@@ -23,6 +48,9 @@ pub struct ReadContext {
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
+    /// Whether the next frame is the peer's handshake
+    awaiting_handshake: bool,
+    opener: Option<secure::Opener>,
 }
 
 impl ReadContext {
@@ -45,6 +73,13 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            // the length is the peer's word, check it before allocating
+                            if message_length as usize > MAX_MESSAGE_LENGTH {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("message of {} bytes is too long", message_length),
+                                ));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -60,7 +95,19 @@ impl ReadContext {
                             self.read_length = 0;
                             self.msg_length = std::mem::size_of::<u32>();
                             trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
+                            if self.awaiting_handshake {
+                                self.awaiting_handshake = false;
+                                return Ok(ReadResult::Handshake(new_payload));
+                            }
+                            match self.opener {
+                                Some(ref mut opener) => match opener.open(new_payload) {
+                                    Ok(payload) => Ok(ReadResult::Message(payload)),
+                                    Err(e) => {
+                                        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                                    }
+                                },
+                                None => Ok(ReadResult::Message(new_payload)),
+                            }
                         }
                     }
                 } else {
@@ -86,11 +133,18 @@ enum WriteState {
 pub struct WriteContext {
     writer: std::io::BufWriter<mio::net::TcpStream>,
     pub queue: channel::Receiver<Vec<u8>>,
+    /// Bytes in the queue, shared with the handle
+    queued_bytes: Arc<AtomicUsize>,
     len_buffer: [u8; std::mem::size_of::<u32>()],
     msg_buffer: Vec<u8>,
     msg_length: usize,
     written_length: usize,
     state: WriteState,
+    /// Our handshake frame, sent before anything else
+    handshake_frame: Option<Vec<u8>>,
+    /// Whether the queue must wait for the session keys
+    awaiting_keys: bool,
+    sealer: Option<secure::Sealer>,
 }
 
 impl WriteContext {
@@ -120,8 +174,27 @@ impl WriteContext {
                         // if the previous message has been fully written, try to get the next message
                         // first flush the writer
                         self.writer.flush()?;
+                        if let Some(frame) = self.handshake_frame.take() {
+                            self.msg_buffer = frame;
+                            self.msg_length = self.msg_buffer.len();
+                            self.len_buffer[..4]
+                                .copy_from_slice(&(self.msg_length as u32).to_be_bytes());
+                            self.written_length = 0;
+                            self.state = WriteState::Length;
+                            continue;
+                        }
+                        if self.awaiting_keys {
+                            // leave the queue alone until the handshake completes
+                            return Ok(WriteResult::Complete);
+                        }
                         let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
+                            Ok(msg) => {
+                                self.queued_bytes.fetch_sub(msg.len(), Ordering::SeqCst);
+                                match self.sealer {
+                                    Some(ref mut sealer) => sealer.seal(msg),
+                                    None => msg,
+                                }
+                            }
                             Err(e) => match e {
                                 mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
                                 mpsc::TryRecvError::Disconnected => {
@@ -158,7 +231,12 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    security: &Security,
 ) -> std::io::Result<(Context, Handle)> {
+    let handshake = match security {
+        Security::Plaintext => None,
+        Security::Encrypted(identity) => Some(secure::start(identity)),
+    };
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
     let addr = stream.peer_addr()?;
@@ -169,22 +247,26 @@ pub fn new(
         msg_length: std::mem::size_of::<u32>(),
         read_length: 0,
         state: DecodeState::Length,
+        awaiting_handshake: handshake.is_some(),
+        opener: None,
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
+    let queued_bytes = Arc::new(AtomicUsize::new(0));
     let write_ctx = WriteContext {
         writer: bufwriter,
         queue: write_receiver,
+        queued_bytes: Arc::clone(&queued_bytes),
         len_buffer: [0; std::mem::size_of::<u32>()],
         msg_buffer: Vec::new(),
         msg_length: 0,
         written_length: 0,
         state: WriteState::Payload,
+        handshake_frame: handshake.as_ref().map(|x| x.frame.clone()),
+        awaiting_keys: handshake.is_some(),
+        sealer: None,
     };
-    let handle = Handle {
-        write_queue: write_sender,
-        addr,
-    };
+    let handle = new_handle(addr, direction, write_sender, queued_bytes);
     let ctx = Context {
         addr,
         stream,
@@ -192,11 +274,80 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        handshake,
     };
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+/// The outgoing frames of a peer created by `new_in_memory`
+pub struct InMemoryQueue {
+    receiver: channel::Receiver<Vec<u8>>,
+    queued_bytes: Arc<AtomicUsize>,
+}
+
+impl InMemoryQueue {
+    pub fn try_recv(&self) -> Result<Vec<u8>, mpsc::TryRecvError> {
+        let frame = self.receiver.try_recv()?;
+        self.queued_bytes.fetch_sub(frame.len(), Ordering::SeqCst);
+        Ok(frame)
+    }
+}
+
+/// Create a peer handle whose outgoing frames are not written to a socket but left in the
+/// returned queue, for transports moving them by other means.
+pub fn new_in_memory(addr: std::net::SocketAddr, direction: Direction) -> (Handle, InMemoryQueue) {
+    let (write_sender, write_receiver) = channel::channel();
+    let queued_bytes = Arc::new(AtomicUsize::new(0));
+    let queue = InMemoryQueue {
+        receiver: write_receiver,
+        queued_bytes: Arc::clone(&queued_bytes),
+    };
+    (
+        new_handle(addr, direction, write_sender, queued_bytes),
+        queue,
+    )
+}
+
+fn new_handle(
+    addr: std::net::SocketAddr,
+    direction: Direction,
+    write_queue: channel::Sender<Vec<u8>>,
+    queued_bytes: Arc<AtomicUsize>,
+) -> Handle {
+    let now = Instant::now();
+    Handle {
+        write_queue,
+        queued_bytes,
+        limiter: Arc::new(Mutex::new(RateLimiter::new())),
+        requests: Arc::new(Mutex::new(Requests::default())),
+        addr,
+        direction,
+        known_inventory: Arc::new(Mutex::new(KnownInventory::new())),
+        liveness: Arc::new(Mutex::new(Liveness {
+            connected_at: now,
+            last_activity: now,
+            last_ping_at: None,
+            pending_ping: None,
+            rtt: None,
+            hello: None,
+            peer_id: None,
+            misbehavior: 0,
+        })),
+        traffic: Arc::new(Mutex::new(Traffic {
+            codec: Compression::None,
+            messages_sent: 0,
+            bytes_sent: 0,
+            raw_bytes_sent: 0,
+            messages_received: 0,
+            bytes_received: 0,
+            raw_bytes_received: 0,
+            rate_limited: 0,
+            dropped_writes: 0,
+        })),
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -209,20 +360,453 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    handshake: Option<secure::Handshake>,
+}
+
+impl Context {
+    /// Check the peer's handshake frame and switch both directions to the session keys.
+    pub fn complete_handshake(&mut self, frame: &[u8]) -> Result<H256, String> {
+        let handshake = match self.handshake.take() {
+            Some(handshake) => handshake,
+            None => return Err("unexpected handshake".to_string()),
+        };
+        let initiator = match self.direction {
+            Direction::Outgoing => true,
+            Direction::Incoming => false,
+        };
+        let (peer_id, sealer, opener) = handshake.finish(frame, initiator)?;
+        self.reader.opener = Some(opener);
+        self.writer.sealer = Some(sealer);
+        self.writer.awaiting_keys = false;
+        self.handle.liveness.lock().unwrap().peer_id = Some(peer_id);
+        Ok(peer_id)
+    }
+}
+
+/// Hashes of blocks and transactions a peer is known to have, forgetting the oldest ones first
+struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownInventory {
+    fn new() -> Self {
+        KnownInventory {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, hash: H256) {
+        if !self.hashes.insert(hash) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+    }
+}
+
+/// Handshake and ping bookkeeping of a peer
+struct Liveness {
+    connected_at: Instant,
+    /// When the peer last sent us a message
+    last_activity: Instant,
+    last_ping_at: Option<Instant>,
+    /// The nonce of the ping we are waiting an answer for, and when it was sent
+    pending_ping: Option<(u64, Instant)>,
+    /// Round trip time of the last answered ping
+    rtt: Option<Duration>,
+    hello: Option<message::Hello>,
+    /// The identity key the peer proved to own, on encrypted connections
+    peer_id: Option<H256>,
+    /// Points for protocol violations, the peer is banned when it gets too high
+    misbehavior: u32,
+}
+
+/// Message and byte counts of a peer, before and after compression
+struct Traffic {
+    /// The codec we compress messages to the peer with
+    codec: Compression,
+    messages_sent: u64,
+    bytes_sent: u64,
+    raw_bytes_sent: u64,
+    messages_received: u64,
+    bytes_received: u64,
+    raw_bytes_received: u64,
+    /// Messages from the peer dropped by the rate limits
+    rate_limited: u64,
+    /// Messages to the peer dropped because its outgoing queue was full
+    dropped_writes: u64,
+}
+
+/// How many times smaller messages got through compression
+fn compression_ratio(raw_bytes: u64, bytes: u64) -> f64 {
+    if bytes == 0 {
+        return 1.0;
+    }
+    raw_bytes as f64 / bytes as f64
+}
+
+/// A snapshot of what we know about a peer
+#[derive(Serialize, Clone, Debug)]
+pub struct Info {
+    pub addr: std::net::SocketAddr,
+    pub direction: Direction,
+    pub peer_id: Option<H256>,
+    pub hello: Option<message::Hello>,
+    pub rtt_ms: Option<u128>,
+    /// Milliseconds since the peer last sent us a message
+    pub idle_ms: u128,
+    pub connected_secs: u64,
+    pub misbehavior: u32,
+    pub compression: Compression,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Size of the messages sent before compression over their size after
+    pub compression_ratio_sent: f64,
+    pub compression_ratio_received: f64,
+    pub queued_bytes: usize,
+    pub rate_limited: u64,
+    pub dropped_writes: u64,
+}
+
+/// Why a message could not be queued for a peer
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    /// The connection is closed
+    Disconnected,
+    /// The peer's outgoing queue is full
+    QueueFull,
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WriteError::Disconnected => write!(f, "peer disconnected"),
+            WriteError::QueueFull => write!(f, "outgoing queue full"),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
     write_queue: channel::Sender<Vec<u8>>,
+    /// Bytes in the outgoing queue
+    queued_bytes: Arc<AtomicUsize>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    requests: Arc<Mutex<Requests>>,
+    known_inventory: Arc<Mutex<KnownInventory>>,
+    liveness: Arc<Mutex<Liveness>>,
+    traffic: Arc<Mutex<Traffic>>,
 }
 
 impl Handle {
-    pub fn write(&self, msg: message::Message) {
-        // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Add to the peer's misbehavior score for a protocol violation.
+    pub fn misbehaved(&self, score: u32, reason: &str) {
+        let mut liveness = self.liveness.lock().unwrap();
+        liveness.misbehavior = liveness.misbehavior.saturating_add(score);
+        info!(
+            "Peer {} misbehaved: {}, score {}",
+            self.addr, reason, liveness.misbehavior
+        );
+    }
+
+    pub fn misbehavior(&self) -> u32 {
+        self.liveness.lock().unwrap().misbehavior
+    }
+
+    /// Record that the peer just sent us a message.
+    pub fn touch(&self) {
+        self.liveness.lock().unwrap().last_activity = Instant::now();
+    }
+
+    /// Record the peer's `Hello`, completing the handshake and picking the compression codec.
+    pub fn handshake_received(&self, hello: message::Hello) {
+        self.traffic.lock().unwrap().codec = compression::negotiate(&hello.compression);
+        self.liveness.lock().unwrap().hello = Some(hello);
+    }
+
+    /// Send a ping with a fresh nonce, unless one is still unanswered.
+    pub fn ping(&self) -> Result<(), WriteError> {
+        let nonce: u64 = rand::thread_rng().gen();
+        {
+            let mut liveness = self.liveness.lock().unwrap();
+            if liveness.pending_ping.is_some() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            liveness.pending_ping = Some((nonce, now));
+            liveness.last_ping_at = Some(now);
+        }
+        let result = self.write(message::Message::Ping(nonce));
+        if result.is_err() {
+            // not sent, so there is no answer to wait for
+            self.liveness.lock().unwrap().pending_ping = None;
+        }
+        result
+    }
+
+    /// Match a pong against our pending ping. Returns the round trip time if it answers it.
+    pub fn pong_received(&self, nonce: u64) -> Option<Duration> {
+        let mut liveness = self.liveness.lock().unwrap();
+        match liveness.pending_ping {
+            Some((pending, sent_at)) if pending == nonce => {
+                let rtt = sent_at.elapsed();
+                liveness.pending_ping = None;
+                liveness.rtt = Some(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Ping the peer if it is due. Returns an error with the reason if the peer should be
+    /// disconnected because it did not complete the handshake or stopped answering pings.
+    pub fn check_liveness(&self) -> Result<(), String> {
+        let now = Instant::now();
+        let due = {
+            let liveness = self.liveness.lock().unwrap();
+            if liveness.hello.is_none()
+                && now.duration_since(liveness.connected_at) > HANDSHAKE_TIMEOUT
+            {
+                return Err("no handshake".to_string());
+            }
+            if let Some((_, sent_at)) = liveness.pending_ping {
+                if now.duration_since(sent_at) > PING_TIMEOUT {
+                    return Err("ping timeout".to_string());
+                }
+            }
+            match liveness.last_ping_at {
+                Some(last_ping_at) => now.duration_since(last_ping_at) >= PING_INTERVAL,
+                None => true,
+            }
+        };
+        if due {
+            match self.ping() {
+                // a congested peer is pinged again at the next interval
+                Ok(()) | Err(WriteError::QueueFull) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> Info {
+        let liveness = self.liveness.lock().unwrap();
+        let traffic = self.traffic.lock().unwrap();
+        Info {
+            addr: self.addr,
+            direction: self.direction,
+            peer_id: liveness.peer_id,
+            hello: liveness.hello.clone(),
+            rtt_ms: liveness.rtt.map(|rtt| rtt.as_millis()),
+            idle_ms: liveness.last_activity.elapsed().as_millis(),
+            connected_secs: liveness.connected_at.elapsed().as_secs(),
+            misbehavior: liveness.misbehavior,
+            compression: traffic.codec,
+            messages_sent: traffic.messages_sent,
+            bytes_sent: traffic.bytes_sent,
+            messages_received: traffic.messages_received,
+            bytes_received: traffic.bytes_received,
+            compression_ratio_sent: compression_ratio(traffic.raw_bytes_sent, traffic.bytes_sent),
+            compression_ratio_received: compression_ratio(
+                traffic.raw_bytes_received,
+                traffic.bytes_received,
+            ),
+            queued_bytes: self.queued_bytes.load(Ordering::SeqCst),
+            rate_limited: traffic.rate_limited,
+            dropped_writes: traffic.dropped_writes,
+        }
+    }
+
+    /// Remember that the peer has the block or transaction with this hash.
+    pub fn mark_known(&self, hash: &H256) {
+        self.known_inventory.lock().unwrap().insert(*hash);
+    }
+
+    /// Whether the peer is known to have the block or transaction with this hash
+    pub fn knows(&self, hash: &H256) -> bool {
+        self.known_inventory.lock().unwrap().hashes.contains(hash)
+    }
+
+    /// Queue a message for the peer.
+    pub fn write(&self, msg: message::Message) -> Result<(), WriteError> {
+        let payload = bincode::serialize(&msg).unwrap();
+        let buffer = {
+            let mut traffic = self.traffic.lock().unwrap();
+            let buffer = compression::encode(&payload, traffic.codec);
+            // drop messages to a peer that does not keep up rather than buffering without bound
+            let queued = self.queued_bytes.load(Ordering::SeqCst);
+            if queued > 0 && queued + buffer.len() > MAX_QUEUED_BYTES {
+                traffic.dropped_writes += 1;
+                warn!(
+                    "Outgoing queue of peer {} is full, dropping message",
+                    self.addr
+                );
+                return Err(WriteError::QueueFull);
+            }
+            traffic.messages_sent += 1;
+            traffic.raw_bytes_sent += payload.len() as u64;
+            traffic.bytes_sent += buffer.len() as u64;
+            buffer
+        };
+        let len = buffer.len();
+        self.queued_bytes.fetch_add(len, Ordering::SeqCst);
         if self.write_queue.send(buffer).is_err() {
-            warn!("Failed to send write request for peer {}, channel detached", self.addr);
+            self.queued_bytes.fetch_sub(len, Ordering::SeqCst);
+            return Err(WriteError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Send a request and track it, so that the peer's response can be awaited with a timeout.
+    pub fn request(&self, msg: message::Message, timeout: Duration) -> Result<Reply, RequestError> {
+        let reply = self.requests.lock().unwrap().register(&msg, timeout)?;
+        self.write(msg).map_err(RequestError::Write)?;
+        Ok(reply)
+    }
+
+    /// Hand a message from the peer to the request it answers, if any. Returns whether it
+    /// answered one.
+    pub fn response_received(&self, msg: &message::Message) -> bool {
+        self.requests.lock().unwrap().resolve(msg)
+    }
+
+    /// Whether the peer's outgoing queue is more than half full
+    pub fn is_congested(&self) -> bool {
+        self.queued_bytes.load(Ordering::SeqCst) > MAX_QUEUED_BYTES / 2
+    }
+
+    /// Charge a message from the peer to its rate limits. Returns an error with the reason if the
+    /// message must be dropped.
+    pub fn allow(&self, msg: &message::Message) -> Result<(), Rejection> {
+        let result = self.limiter.lock().unwrap().check(msg, Instant::now());
+        if result.is_err() {
+            self.traffic.lock().unwrap().rate_limited += 1;
         }
+        result
+    }
+
+    /// Decompress and deserialize a message received from the peer.
+    pub fn decode(&self, frame: &[u8]) -> Result<message::Message, String> {
+        let payload = compression::decode(frame)?;
+        {
+            let mut traffic = self.traffic.lock().unwrap();
+            traffic.messages_received += 1;
+            traffic.raw_bytes_received += payload.len() as u64;
+            traffic.bytes_received += frame.len() as u64;
+        }
+        bincode::deserialize(&payload).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn known_inventory_is_bounded() {
+        let mut known = KnownInventory::new();
+        let first = generate_random_hash();
+        known.insert(first);
+        known.insert(first);
+        assert_eq!(known.order.len(), 1);
+        for _ in 0..MAX_KNOWN_INVENTORY {
+            known.insert(generate_random_hash());
+        }
+        assert_eq!(known.hashes.len(), MAX_KNOWN_INVENTORY);
+        assert!(!known.hashes.contains(&first));
+    }
+
+    #[test]
+    fn compression_is_negotiated() {
+        let addr: std::net::SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let (sender, queue) = new_in_memory(addr, Direction::Outgoing);
+        let (receiver, _) = new_in_memory(addr, Direction::Incoming);
+        let msg = message::Message::GetBlock(vec![generate_random_hash(); 100]);
+
+        sender.write(msg.clone()).unwrap();
+        let frame = queue.try_recv().unwrap();
+        assert_eq!(frame[0], Compression::None as u8);
+        assert!(receiver.decode(&frame).is_ok());
+
+        sender.handshake_received(message::Hello {
+            version: message::PROTOCOL_VERSION,
+            user_agent: String::new(),
+            listen_addr: addr,
+            compression: compression::SUPPORTED.to_vec(),
+        });
+        sender.write(msg).unwrap();
+        let compressed = queue.try_recv().unwrap();
+        assert_eq!(compressed[0], Compression::Lz4 as u8);
+        assert!(compressed.len() < frame.len());
+        match receiver.decode(&compressed).unwrap() {
+            message::Message::GetBlock(hashes) => assert_eq!(hashes.len(), 100),
+            _ => panic!("wrong message"),
+        }
+        let info = sender.info();
+        assert_eq!(info.messages_sent, 2);
+        assert!(info.compression_ratio_sent > 1.0);
+        assert!(receiver.info().compression_ratio_received > 1.0);
+        assert!(receiver.decode(&[7, 0]).is_err());
+    }
+
+    #[test]
+    fn outgoing_queue_is_bounded() {
+        let addr: std::net::SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let (handle, queue) = new_in_memory(addr, Direction::Outgoing);
+        let msg = message::Message::GetBlock(vec![generate_random_hash(); 10000]);
+        let dropped = (0..60)
+            .filter(|_| handle.write(msg.clone()) == Err(WriteError::QueueFull))
+            .count();
+        let info = handle.info();
+        assert!(dropped > 0);
+        assert_eq!(info.dropped_writes, dropped as u64);
+        assert!(info.queued_bytes <= MAX_QUEUED_BYTES);
+        assert!(handle.is_congested());
+        // a full queue does not make the peer look dead
+        while handle.write(message::Message::Ping(0)).is_ok() {}
+        assert!(handle.check_liveness().is_ok());
+        assert!(handle.liveness.lock().unwrap().pending_ping.is_none());
+        while queue.try_recv().is_ok() {}
+        assert_eq!(handle.info().queued_bytes, 0);
+        assert!(!handle.is_congested());
+    }
+
+    #[test]
+    fn incoming_frames_are_bounded() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = mio::net::TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let (mut ctx, _) = new(stream, Direction::Incoming, &Security::Plaintext).unwrap();
+        remote.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let error = loop {
+            match ctx.reader.read() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => break e,
+                Ok(ReadResult::Continue) => {}
+                Ok(_) => panic!("unexpected read result"),
+            }
+        };
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(ctx.reader.buffer.len() <= MAX_MESSAGE_LENGTH);
     }
 }
@@ -0,0 +1,202 @@
+//! Per-peer limits on what a peer can make us do.
+//!
+//! Each peer has one token bucket for all its messages, and one per kind of request that makes
+//! us read from the blockchain or the mempool, charged per requested item. A message arriving
+//! when its bucket is empty is dropped, and so is a request larger than any peer may send.
+
+use super::message::{
+    Message, MAX_BLOCKS_PER_REQUEST, MAX_LOCATOR_LENGTH, MAX_TRANSACTIONS_PER_REQUEST,
+};
+
+use std::time::Instant;
+
+/// A bucket refilling at `rate` tokens per second, up to `burst` tokens
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take `count` tokens if the bucket has them.
+    pub fn take(&mut self, count: f64, now: Instant) -> bool {
+        if now > self.refilled_at {
+            let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.refilled_at = now;
+        }
+        if self.tokens < count {
+            return false;
+        }
+        self.tokens -= count;
+        true
+    }
+}
+
+/// Why a message was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The peer sends faster than its limits allow
+    RateLimited(String),
+    /// The request is larger than the protocol allows
+    Oversized(String),
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::RateLimited(reason) | Rejection::Oversized(reason) => {
+                write!(f, "{}", reason)
+            }
+        }
+    }
+}
+
+/// The token buckets of one peer
+pub struct RateLimiter {
+    messages: TokenBucket,
+    blocks: TokenBucket,
+    transactions: TokenBucket,
+    headers: TokenBucket,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(200.0, 1000.0),
+            blocks: TokenBucket::new(100.0, 500.0),
+            transactions: TokenBucket::new(1000.0, 5000.0),
+            headers: TokenBucket::new(5.0, 20.0),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Charge a message received at `now` to the buckets. Returns an error with the reason if it
+    /// must be dropped.
+    pub fn check(&mut self, msg: &Message, now: Instant) -> Result<(), Rejection> {
+        let (bucket, items, max_items, kind) = match msg {
+            Message::GetBlock(hashes) => (
+                &mut self.blocks,
+                hashes.len(),
+                MAX_BLOCKS_PER_REQUEST,
+                "GetBlock",
+            ),
+            Message::GetCompactBlock(hashes) => (
+                &mut self.blocks,
+                hashes.len(),
+                MAX_BLOCKS_PER_REQUEST,
+                "GetCompactBlock",
+            ),
+            Message::GetBlockTransactions(..) => (&mut self.blocks, 1, 1, "GetBlockTransactions"),
+            Message::GetTransaction(hashes) => (
+                &mut self.transactions,
+                hashes.len(),
+                MAX_TRANSACTIONS_PER_REQUEST,
+                "GetTransaction",
+            ),
+            // a locator costs a lookup per hash, but the headers sent are what the bucket limits
+            Message::GetHeaders(locator) => {
+                if locator.len() > MAX_LOCATOR_LENGTH {
+                    return Err(Rejection::Oversized(format!(
+                        "GetHeaders with {} locator hashes",
+                        locator.len()
+                    )));
+                }
+                (&mut self.headers, 1, 1, "GetHeaders")
+            }
+            _ => {
+                return if self.messages.take(1.0, now) {
+                    Ok(())
+                } else {
+                    Err(Rejection::RateLimited(
+                        "message rate limit exceeded".to_string(),
+                    ))
+                };
+            }
+        };
+        if items > max_items {
+            return Err(Rejection::Oversized(format!(
+                "{} for {} items",
+                kind, items
+            )));
+        }
+        if !self.messages.take(1.0, now) {
+            return Err(Rejection::RateLimited(
+                "message rate limit exceeded".to_string(),
+            ));
+        }
+        if !bucket.take(items as f64, now) {
+            return Err(Rejection::RateLimited(format!(
+                "{} rate limit exceeded",
+                kind
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        let mut bucket = TokenBucket::new(10.0, 20.0);
+        let start = Instant::now();
+        assert!(bucket.take(20.0, start));
+        assert!(!bucket.take(1.0, start));
+        assert!(bucket.take(5.0, start + Duration::from_millis(500)));
+        assert!(!bucket.take(1.0, start + Duration::from_millis(500)));
+        // the bucket never holds more than the burst
+        assert!(!bucket.take(21.0, start + Duration::from_secs(60)));
+
+        let mut limiter = RateLimiter::new();
+        let too_many = Message::GetBlock(vec![generate_random_hash(); MAX_BLOCKS_PER_REQUEST + 1]);
+        match limiter.check(&too_many, start) {
+            Err(Rejection::Oversized(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let request = Message::GetBlock(vec![generate_random_hash(); MAX_BLOCKS_PER_REQUEST]);
+        let allowed = (0..10)
+            .filter(|_| limiter.check(&request, start).is_ok())
+            .count();
+        assert_eq!(allowed, 500 / MAX_BLOCKS_PER_REQUEST);
+        // other kinds of messages have their own buckets
+        assert!(limiter.check(&Message::Ping(1), start).is_ok());
+        assert!(limiter
+            .check(
+                &Message::GetTransaction(vec![generate_random_hash()]),
+                start
+            )
+            .is_ok());
+        assert!(limiter
+            .check(&request, start + Duration::from_secs(2))
+            .is_ok());
+
+        // a locator is bounded whatever the bucket holds
+        let locator = vec![generate_random_hash(); MAX_LOCATOR_LENGTH + 1];
+        match limiter.check(&Message::GetHeaders(locator), start) {
+            Err(Rejection::Oversized(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let locator = vec![generate_random_hash(); MAX_LOCATOR_LENGTH];
+        assert!(limiter.check(&Message::GetHeaders(locator), start).is_ok());
+    }
+}
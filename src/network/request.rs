@@ -0,0 +1,214 @@
+//! Matching peer responses to our requests.
+//!
+//! Messages carry no request IDs, so a response is matched by content: a `Block` answers the
+//! `GetBlock` that asked for one of its hashes, a `Pong` the `Ping` with its nonce, and a
+//! `Headers` the oldest pending `GetHeaders`.
+
+use super::message::Message;
+use super::peer::WriteError;
+use crate::crypto::hash::{Hashable, H256};
+use crossbeam::channel;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Why a request got no reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// The message does not expect a response
+    NotARequest,
+    /// The request could not be queued
+    Write(WriteError),
+    Timeout,
+    /// The peer went away before answering
+    Disconnected,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::NotARequest => write!(f, "message does not expect a response"),
+            RequestError::Write(e) => write!(f, "{}", e),
+            RequestError::Timeout => write!(f, "request timed out"),
+            RequestError::Disconnected => write!(f, "peer disconnected"),
+        }
+    }
+}
+
+/// What answers a request
+enum Expect {
+    Blocks(HashSet<H256>),
+    CompactBlocks(HashSet<H256>),
+    Transactions(HashSet<H256>),
+    BlockTransactions(H256),
+    Headers,
+    Pong(u64),
+}
+
+impl Expect {
+    fn of(request: &Message) -> Option<Self> {
+        let expect = match request {
+            Message::GetBlock(hashes) => Expect::Blocks(hashes.iter().cloned().collect()),
+            Message::GetCompactBlock(hashes) => {
+                Expect::CompactBlocks(hashes.iter().cloned().collect())
+            }
+            Message::GetTransaction(hashes) => {
+                Expect::Transactions(hashes.iter().cloned().collect())
+            }
+            Message::GetBlockTransactions(hash, _) => Expect::BlockTransactions(*hash),
+            Message::GetHeaders(_) => Expect::Headers,
+            Message::Ping(nonce) => Expect::Pong(*nonce),
+            _ => return None,
+        };
+        Some(expect)
+    }
+
+    fn matches(&self, response: &Message) -> bool {
+        match (self, response) {
+            (Expect::Blocks(hashes), Message::Block(blocks)) => {
+                blocks.iter().any(|x| hashes.contains(&x.hash()))
+            }
+            (Expect::CompactBlocks(hashes), Message::CompactBlock(blocks)) => {
+                blocks.iter().any(|x| hashes.contains(&x.hash()))
+            }
+            (Expect::Transactions(hashes), Message::Transaction(transactions)) => {
+                transactions.iter().any(|x| hashes.contains(&x.hash()))
+            }
+            (Expect::BlockTransactions(hash), Message::BlockTransactions(answered, _)) => {
+                hash == answered
+            }
+            // a bad index is answered with the whole block
+            (Expect::BlockTransactions(hash), Message::Block(blocks)) => {
+                blocks.iter().any(|x| x.hash() == *hash)
+            }
+            (Expect::Headers, Message::Headers(_)) => true,
+            (Expect::Pong(nonce), Message::Pong(answered)) => nonce == answered,
+            _ => false,
+        }
+    }
+}
+
+struct Pending {
+    expect: Expect,
+    reply: channel::Sender<Message>,
+    deadline: Instant,
+}
+
+/// The requests to one peer waiting for a response
+#[derive(Default)]
+pub struct Requests {
+    pending: Vec<Pending>,
+}
+
+impl Requests {
+    /// Track a request sent now. Fails if the message does not expect a response.
+    pub fn register(
+        &mut self,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<Reply, RequestError> {
+        let expect = Expect::of(request).ok_or(RequestError::NotARequest)?;
+        let now = Instant::now();
+        self.pending.retain(|x| x.deadline > now);
+        let (sender, receiver) = channel::bounded(1);
+        self.pending.push(Pending {
+            expect,
+            reply: sender,
+            deadline: now + timeout,
+        });
+        Ok(Reply {
+            receiver,
+            deadline: now + timeout,
+        })
+    }
+
+    /// Hand a message from the peer to the oldest request it answers, if any. Returns whether it
+    /// answered one.
+    pub fn resolve(&mut self, response: &Message) -> bool {
+        let now = Instant::now();
+        self.pending.retain(|x| x.deadline > now);
+        match self.pending.iter().position(|x| x.expect.matches(response)) {
+            Some(i) => {
+                let pending = self.pending.remove(i);
+                // the waiter may have given up already
+                let _ = pending.reply.send(response.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The response to a request, once it arrives
+pub struct Reply {
+    receiver: channel::Receiver<Message>,
+    deadline: Instant,
+}
+
+impl Reply {
+    /// Block until the response arrives or the request times out.
+    pub fn wait(self) -> Result<Message, RequestError> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        self.receiver.recv_timeout(timeout).map_err(|e| {
+            if e.is_timeout() {
+                RequestError::Timeout
+            } else {
+                RequestError::Disconnected
+            }
+        })
+    }
+
+    /// The response if it has arrived.
+    pub fn try_get(&self) -> Option<Message> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Whether the request timed out, answered or not.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    #[test]
+    fn correlate() {
+        let mut requests = Requests::default();
+        let first = generate_random_block(&H256::from([0; 32]));
+        let second = generate_random_block(&first.hash());
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            requests.register(&Message::Pong(1), timeout).err(),
+            Some(RequestError::NotARequest)
+        );
+        let ping = requests.register(&Message::Ping(7), timeout).unwrap();
+        let get_second = requests
+            .register(&Message::GetBlock(vec![second.hash()]), timeout)
+            .unwrap();
+        let get_first = requests
+            .register(&Message::GetBlock(vec![first.hash()]), timeout)
+            .unwrap();
+
+        assert!(!requests.resolve(&Message::Pong(8)));
+        assert!(ping.try_get().is_none());
+        assert!(requests.resolve(&Message::Block(vec![first.clone()])));
+        requests.resolve(&Message::Pong(7));
+        match get_first.wait() {
+            Ok(Message::Block(blocks)) => assert_eq!(blocks[0].hash(), first.hash()),
+            _ => panic!("wrong response"),
+        }
+        assert!(ping.wait().is_ok());
+        assert!(get_second.try_get().is_none());
+
+        let late = requests
+            .register(&Message::GetHeaders(vec![]), Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(late.wait().err(), Some(RequestError::Timeout));
+        // the expired request is forgotten
+        requests.resolve(&Message::Headers(vec![]));
+        assert_eq!(requests.pending.len(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+//! Authenticated encryption of peer connections.
+//!
+//! Right after connecting, each side sends one plaintext handshake frame made of its static
+//! Ed25519 identity key, a fresh X25519 ephemeral key, and an Ed25519 signature over the
+//! ephemeral key. Both sides then run X25519 and derive one ChaCha20-Poly1305 key per direction
+//! with HKDF-SHA256. Every later frame is sealed with the sender's key and a message counter as
+//! the nonce, so dropped, reordered or replayed frames fail to open.
+
+use crate::crypto::hash::H256;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use ring::{aead, agreement, hkdf, rand};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+const HANDSHAKE_CONTEXT: &[u8] = b"bitcoin-p2p-handshake-v1";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const HANDSHAKE_LEN: usize = KEY_LEN + KEY_LEN + SIGNATURE_LEN;
+
+/// How the bytes of a peer connection are protected
+#[derive(Clone)]
+pub enum Security {
+    /// Raw bincode over TCP, for local debugging
+    Plaintext,
+    /// Authenticated encryption, proving we own this identity key
+    Encrypted(Arc<Ed25519KeyPair>),
+}
+
+/// The peer ID of a node, which is its Ed25519 identity public key
+pub fn peer_id(identity: &Ed25519KeyPair) -> H256 {
+    let public_key: [u8; 32] = identity.public_key().as_ref().try_into().unwrap();
+    public_key.into()
+}
+
+/// Our half of a handshake in progress
+pub struct Handshake {
+    ephemeral: agreement::EphemeralPrivateKey,
+    ephemeral_public: [u8; KEY_LEN],
+    /// The frame to send to the peer
+    pub frame: Vec<u8>,
+}
+
+/// Seals outgoing frames
+pub struct Sealer {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+/// Opens incoming frames
+pub struct Opener {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+/// Start a handshake with a fresh ephemeral key.
+pub fn start(identity: &Ed25519KeyPair) -> Handshake {
+    let rng = rand::SystemRandom::new();
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+    let ephemeral_public: [u8; KEY_LEN] = ephemeral
+        .compute_public_key()
+        .unwrap()
+        .as_ref()
+        .try_into()
+        .unwrap();
+    let signature = identity.sign(&[HANDSHAKE_CONTEXT, &ephemeral_public[..]].concat());
+    let frame = [
+        identity.public_key().as_ref(),
+        &ephemeral_public[..],
+        signature.as_ref(),
+    ]
+    .concat();
+    Handshake {
+        ephemeral,
+        ephemeral_public,
+        frame,
+    }
+}
+
+impl Handshake {
+    /// Check the peer's handshake frame and derive the session keys. `initiator` tells whether
+    /// we opened the connection. Returns the peer ID with the keys for each direction.
+    pub fn finish(self, frame: &[u8], initiator: bool) -> Result<(H256, Sealer, Opener), String> {
+        if frame.len() != HANDSHAKE_LEN {
+            return Err(format!("bad handshake length {}", frame.len()));
+        }
+        let identity = &frame[..KEY_LEN];
+        let ephemeral_public = &frame[KEY_LEN..2 * KEY_LEN];
+        let signature = &frame[2 * KEY_LEN..];
+        UnparsedPublicKey::new(&ED25519, identity)
+            .verify(&[HANDSHAKE_CONTEXT, ephemeral_public].concat(), signature)
+            .map_err(|_| "bad handshake signature".to_string())?;
+        let identity: [u8; KEY_LEN] = identity.try_into().unwrap();
+
+        // bind both ephemeral keys into the key derivation, initiator first
+        let salt = if initiator {
+            [&self.ephemeral_public[..], ephemeral_public].concat()
+        } else {
+            [ephemeral_public, &self.ephemeral_public[..]].concat()
+        };
+        let peer_public = agreement::UnparsedPublicKey::new(&agreement::X25519, ephemeral_public);
+        let (initiator_key, responder_key) = agreement::agree_ephemeral(
+            self.ephemeral,
+            &peer_public,
+            "key agreement failed".to_string(),
+            |shared_secret| {
+                let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared_secret);
+                Ok((
+                    derive_key(&prk, b"initiator"),
+                    derive_key(&prk, b"responder"),
+                ))
+            },
+        )?;
+        let (sealing_key, opening_key) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        Ok((
+            identity.into(),
+            Sealer {
+                key: sealing_key,
+                counter: 0,
+            },
+            Opener {
+                key: opening_key,
+                counter: 0,
+            },
+        ))
+    }
+}
+
+fn derive_key(prk: &hkdf::Prk, info: &'static [u8]) -> aead::LessSafeKey {
+    let info = [info];
+    let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).unwrap();
+    aead::LessSafeKey::new(aead::UnboundKey::from(okm))
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+impl Sealer {
+    pub fn seal(&mut self, mut frame: Vec<u8>) -> Vec<u8> {
+        self.key
+            .seal_in_place_append_tag(nonce(self.counter), aead::Aad::empty(), &mut frame)
+            .unwrap();
+        self.counter += 1;
+        frame
+    }
+}
+
+impl Opener {
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, String> {
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce(self.counter), aead::Aad::empty(), &mut frame)
+            .map_err(|_| "frame failed authentication".to_string())?
+            .len();
+        self.counter += 1;
+        frame.truncate(plaintext_len);
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+
+    #[test]
+    fn handshake_and_roundtrip() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let alice_handshake = start(&alice);
+        let bob_handshake = start(&bob);
+        let alice_frame = alice_handshake.frame.clone();
+        let (bob_id, mut alice_sealer, mut alice_opener) =
+            alice_handshake.finish(&bob_handshake.frame, true).unwrap();
+        let (alice_id, mut bob_sealer, mut bob_opener) =
+            bob_handshake.finish(&alice_frame, false).unwrap();
+        assert_eq!(bob_id, peer_id(&bob));
+        assert_eq!(alice_id, peer_id(&alice));
+
+        for i in 0..3u8 {
+            let sealed = alice_sealer.seal(vec![i; 10]);
+            assert_ne!(&sealed[..10], &[i; 10][..]);
+            assert_eq!(bob_opener.open(sealed).unwrap(), vec![i; 10]);
+        }
+        let sealed = bob_sealer.seal(b"pong".to_vec());
+        assert_eq!(alice_opener.open(sealed).unwrap(), b"pong".to_vec());
+
+        // a replayed or tampered frame does not open
+        let sealed = alice_sealer.seal(b"once".to_vec());
+        assert!(bob_opener.open(sealed.clone()).is_ok());
+        assert!(bob_opener.open(sealed).is_err());
+    }
+
+    #[test]
+    fn forged_handshake() {
+        let alice = key_pair::random();
+        let mallory = key_pair::random();
+        let mut frame = start(&mallory).frame;
+        // claim to be alice without her key
+        frame[..KEY_LEN].copy_from_slice(alice.public_key().as_ref());
+        assert!(start(&alice).finish(&frame, true).is_err());
+        assert!(start(&alice).finish(&frame[1..], true).is_err());
+    }
+}
@@ -1,18 +1,40 @@
+use super::compression;
 use super::message;
-use super::peer::{self, ReadResult, WriteResult};
+use super::peer::{self, ReadResult, WriteError, WriteResult};
+use super::secure::Security;
+use crate::crypto::hash::H256;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod memory;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// How long new block and transaction hashes are collected before they are announced
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+/// How often peers are checked for liveness and pinged
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long we try to flush the goodbye messages when shutting down
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long dialing a peer may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The misbehavior score at which a peer is banned
+const BAN_THRESHOLD: u32 = 100;
+/// How long a peer is banned for reaching the misbehavior threshold
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    security: Security,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
@@ -22,9 +44,15 @@ pub fn new(
         peers: slab::Slab::new(),
         peer_list: vec![],
         addr,
+        security,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        pending_blocks: vec![],
+        pending_transactions: vec![],
+        announce_deadline: None,
+        banned: HashMap::new(),
+        shutting_down: false,
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -34,21 +62,33 @@ pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
     addr: std::net::SocketAddr,
+    security: Security,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    /// Block hashes waiting for the next announcement
+    pending_blocks: Vec<H256>,
+    /// Transaction hashes waiting for the next announcement
+    pending_transactions: Vec<H256>,
+    /// When the pending hashes are due to be announced
+    announce_deadline: Option<Instant>,
+    /// Banned IP addresses, until when
+    banned: HashMap<IpAddr, Instant>,
+    shutting_down: bool,
     _handle: Handle,
 }
 
 impl Context {
     /// Start a new server context.
-    pub fn start(mut self) -> std::io::Result<()> {
-        thread::spawn(move || {
-            self.listen().unwrap_or_else(|e| {
-                error!("P2P server error: {}", e);
-            });
-        });
-        Ok(())
+    pub fn start(mut self) -> std::io::Result<thread::JoinHandle<()>> {
+        let handle = thread::Builder::new()
+            .name("p2p-server".to_string())
+            .spawn(move || {
+                self.listen().unwrap_or_else(|e| {
+                    error!("P2P server error: {}", e);
+                });
+            })?;
+        Ok(handle)
     }
 
     /// Register a TCP stream in the event loop, and initialize peer context.
@@ -79,7 +119,15 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, &self.security)?;
+        let hello = message::Message::Hello(message::Hello {
+            version: message::PROTOCOL_VERSION,
+            user_agent: format!("bitcoin/{}", env!("CARGO_PKG_VERSION")),
+            listen_addr: self.addr,
+            compression: compression::SUPPORTED.to_vec(),
+        });
+        // the queue of a new peer is empty and connected, so this cannot fail
+        handle.write(hello).unwrap();
 
         // register the writer queue
         self.poll.register(
@@ -97,11 +145,86 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Drop a peer from the connection set, closing its socket.
+    fn remove_peer(&mut self, peer_id: usize) {
+        self.peers.remove(peer_id);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
+    /// Say goodbye to a peer and close its connection without waiting for a slow socket.
+    fn close_peer(&mut self, peer_id: usize, reason: &str) {
+        let peer = &mut self.peers[peer_id];
+        info!("Disconnecting peer {}: {}", peer.addr, reason);
+        if peer
+            .handle
+            .write(message::Message::Goodbye(reason.to_string()))
+            .is_ok()
+        {
+            if let Err(e) = peer.writer.write() {
+                trace!("Cannot flush goodbye to peer {}: {}", peer.addr, e);
+            }
+        }
+        if let Err(e) = peer.stream.shutdown(std::net::Shutdown::Both) {
+            debug!("Error closing connection to peer {}: {}", peer.addr, e);
+        }
+        self.remove_peer(peer_id);
+    }
+
+    /// Whether an address is banned, forgetting the bans that expired
+    fn is_banned(&mut self, ip: &IpAddr) -> bool {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(ip)
+    }
+
+    /// Ban an address and disconnect its peers. Returns the number of peers disconnected.
+    fn ban(&mut self, ip: IpAddr, duration: Duration) -> usize {
+        info!("Banning {} for {:?}", ip, duration);
+        self.banned.insert(ip, Instant::now() + duration);
+        let banned: Vec<usize> = self
+            .peer_list
+            .iter()
+            .cloned()
+            .filter(|x| self.peers[*x].addr.ip() == ip)
+            .collect();
+        for peer_id in banned.iter() {
+            self.close_peer(*peer_id, "banned");
+        }
+        banned.len()
+    }
+
+    /// Ping the peers that are due, disconnect the ones that stopped answering or never
+    /// completed the handshake, and ban the ones that misbehaved too much.
+    fn check_peers(&mut self) {
+        let mut dead: Vec<usize> = vec![];
+        let mut misbehaving: Vec<IpAddr> = vec![];
+        for peer_id in &self.peer_list {
+            let peer = &self.peers[*peer_id];
+            if peer.handle.misbehavior() >= BAN_THRESHOLD {
+                misbehaving.push(peer.addr.ip());
+            } else if let Err(reason) = peer.handle.check_liveness() {
+                info!("Disconnecting peer {}: {}", peer.addr, reason);
+                dead.push(*peer_id);
+            }
+        }
+        for peer_id in dead {
+            self.remove_peer(peer_id);
+        }
+        for ip in misbehaving {
+            self.ban(ip, DEFAULT_BAN_DURATION);
+        }
+    }
+
+    /// Register a connection we dialed
+    fn connect(&mut self, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
+        let addr = stream.peer_addr()?;
+        if self.is_banned(&addr.ip()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "address is banned",
+            ));
+        }
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -113,6 +236,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.is_banned(&addr.ip()) {
+            debug!("Refusing banned peer {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -128,19 +255,120 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(req.stream);
                 req.result_chan.send(handle).unwrap();
             }
-            ControlSignal::BroadcastMessage(msg) => {
+            ControlSignal::BroadcastMessage(msg, result_chan) => {
                 trace!("Processing BroadcastMessage command");
-                for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                let handles = self.peer_list.iter().map(|x| &self.peers[*x].handle);
+                let delivery = deliver(handles, |peer| peer.write(msg.clone()));
+                result_chan.send(delivery).unwrap();
+            }
+            ControlSignal::PingAll(result_chan) => {
+                trace!("Processing PingAll command");
+                let handles = self.peer_list.iter().map(|x| &self.peers[*x].handle);
+                result_chan
+                    .send(deliver(handles, |peer| peer.ping()))
+                    .unwrap();
+            }
+            ControlSignal::Disconnect(addr, result_chan) => {
+                trace!("Processing Disconnect command");
+                let peer_id = self
+                    .peer_list
+                    .iter()
+                    .cloned()
+                    .find(|x| self.peers[*x].addr == addr);
+                if let Some(peer_id) = peer_id {
+                    self.close_peer(peer_id, "disconnected by operator");
                 }
+                result_chan.send(peer_id.is_some()).unwrap();
+            }
+            ControlSignal::Ban(ip, duration, result_chan) => {
+                trace!("Processing Ban command");
+                result_chan.send(self.ban(ip, duration)).unwrap();
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| self.peers[*peer_id].handle.info())
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::AnnounceBlocks(hashes) => {
+                trace!("Processing AnnounceBlocks command");
+                self.pending_blocks.extend(hashes);
+                self.schedule_announcement();
+            }
+            ControlSignal::AnnounceTransactions(hashes) => {
+                trace!("Processing AnnounceTransactions command");
+                self.pending_transactions.extend(hashes);
+                self.schedule_announcement();
+            }
+            ControlSignal::Shutdown => {
+                trace!("Processing Shutdown command");
+                self.disconnect_all();
+                self.shutting_down = true;
             }
         }
         Ok(())
     }
 
+    /// Send a goodbye to every peer, flush the outgoing queues as far as the sockets allow, and
+    /// close the connections.
+    fn disconnect_all(&mut self) {
+        info!(
+            "P2P server shutting down, disconnecting {} peers",
+            self.peer_list.len()
+        );
+        if self.announce_deadline.is_some() {
+            self.announce();
+        }
+        for peer_id in &self.peer_list {
+            let handle = &self.peers[*peer_id].handle;
+            let goodbye = message::Message::Goodbye("node shutting down".to_string());
+            if let Err(e) = handle.write(goodbye) {
+                debug!("Cannot say goodbye to peer {}: {}", handle.addr(), e);
+            }
+        }
+        let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        for peer_id in self.peer_list.clone() {
+            let peer = &mut self.peers[peer_id];
+            loop {
+                match peer.writer.write() {
+                    Err(ref e)
+                        if e.kind() == std::io::ErrorKind::WouldBlock
+                            && Instant::now() < deadline =>
+                    {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    _ => break,
+                }
+            }
+            if let Err(e) = peer.stream.shutdown(std::net::Shutdown::Both) {
+                debug!("Error closing connection to peer {}: {}", peer.addr, e);
+            }
+            self.remove_peer(peer_id);
+        }
+    }
+
+    fn schedule_announcement(&mut self) {
+        if self.announce_deadline.is_none() {
+            self.announce_deadline = Some(Instant::now() + ANNOUNCE_INTERVAL);
+        }
+    }
+
+    /// Announce the pending hashes to every peer not known to have them.
+    fn announce(&mut self) {
+        let blocks = std::mem::take(&mut self.pending_blocks);
+        let transactions = std::mem::take(&mut self.pending_transactions);
+        self.announce_deadline = None;
+        let handles = self.peer_list.iter().map(|x| &self.peers[*x].handle);
+        let delivery = deliver(handles, |peer| announce_to(peer, &blocks, &transactions));
+        log_announcement(blocks.len(), transactions.len(), &delivery);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -164,9 +392,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -174,8 +400,25 @@ impl Context {
                     // no full message has been received
                     continue;
                 }
+                Ok(ReadResult::Handshake(frame)) => match peer.complete_handshake(&frame) {
+                    Ok(id) => {
+                        info!("Peer {} authenticated as {}", peer.addr, id);
+                        // the queued messages can go out now
+                        self.register_write_interest(peer_id)?;
+                        return self.process_readable(peer_id);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Handshake with peer {} failed, disconnecting: {}",
+                            peer.addr, e
+                        );
+                        self.remove_peer(peer_id);
+                        break;
+                    }
+                },
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
+                    peer.handle.touch();
                     // we just received a full message
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
@@ -187,9 +430,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -224,9 +465,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +485,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -283,8 +520,27 @@ impl Context {
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
+        let mut next_maintenance = Instant::now() + MAINTENANCE_INTERVAL;
+
         loop {
-            self.poll.poll(&mut events, None)?;
+            let deadline = match self.announce_deadline {
+                Some(deadline) if deadline < next_maintenance => deadline,
+                _ => next_maintenance,
+            };
+            self.poll.poll(
+                &mut events,
+                Some(deadline.saturating_duration_since(Instant::now())),
+            )?;
+            let now = Instant::now();
+            if let Some(deadline) = self.announce_deadline {
+                if now >= deadline {
+                    self.announce();
+                }
+            }
+            if now >= next_maintenance {
+                self.check_peers();
+                next_maintenance = now + MAINTENANCE_INTERVAL;
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -296,6 +552,10 @@ impl Context {
                             match self.control_chan.try_recv() {
                                 Ok(req) => {
                                     self.process_control(req).unwrap();
+                                    if self.shutting_down {
+                                        info!("P2P server stopped");
+                                        return Ok(());
+                                    }
                                 }
                                 Err(e) => match e {
                                     mpsc::TryRecvError::Empty => break,
@@ -364,37 +624,348 @@ impl Context {
     }
 }
 
+/// Which peers a message was queued for
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Delivery {
+    pub delivered: usize,
+    pub failed: Vec<(std::net::SocketAddr, WriteError)>,
+}
+
+/// The P2P server thread is not running anymore
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stopped;
+
+impl std::fmt::Display for Stopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "P2P server stopped")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
 }
 
 impl Handle {
+    /// Connect to a peer. The address is dialed on the calling thread, so that a slow address
+    /// never holds up the event loop.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        debug!("Establishing connection to peer {}", addr);
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
-            addr,
+            stream,
             result_chan: sender,
         };
-        self.control_chan
-            .send(ControlSignal::ConnectNewPeer(request))
-            .unwrap();
-        receiver.recv().unwrap()
+        if !self.send(ControlSignal::ConnectNewPeer(request)) {
+            return Err(std::io::Error::other(Stopped));
+        }
+        receiver
+            .recv()
+            .map_err(|_| std::io::Error::other(Stopped))?
     }
 
-    pub fn broadcast(&self, msg: message::Message) {
-        self.control_chan
-            .send(ControlSignal::BroadcastMessage(msg))
-            .unwrap();
+    /// Send a message to every peer, reporting which peers it could be queued for.
+    pub fn broadcast(&self, msg: message::Message) -> Result<Delivery, Stopped> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if !self.send(ControlSignal::BroadcastMessage(msg, sender)) {
+            return Err(Stopped);
+        }
+        receiver.recv().map_err(|_| Stopped)
+    }
+
+    /// Ping every peer, measuring the round trip time.
+    pub fn ping(&self) -> Result<Delivery, Stopped> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if !self.send(ControlSignal::PingAll(sender)) {
+            return Err(Stopped);
+        }
+        receiver.recv().map_err(|_| Stopped)
     }
+
+    /// List the connected peers.
+    pub fn peers(&self) -> Result<Vec<peer::Info>, Stopped> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if !self.send(ControlSignal::ListPeers(sender)) {
+            return Err(Stopped);
+        }
+        receiver.recv().map_err(|_| Stopped)
+    }
+
+    /// Disconnect the peer with this address. Returns whether there was one.
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> Result<bool, Stopped> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if !self.send(ControlSignal::Disconnect(addr, sender)) {
+            return Err(Stopped);
+        }
+        receiver.recv().map_err(|_| Stopped)
+    }
+
+    /// Refuse connections from and to an address for a while, disconnecting its peers. Returns
+    /// the number of peers disconnected.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) -> Result<usize, Stopped> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if !self.send(ControlSignal::Ban(ip, duration, sender)) {
+            return Err(Stopped);
+        }
+        receiver.recv().map_err(|_| Stopped)
+    }
+
+    /// Announce new blocks to the peers that do not have them yet, batched on a short timer.
+    pub fn announce_blocks(&self, hashes: Vec<H256>) {
+        self.send(ControlSignal::AnnounceBlocks(hashes));
+    }
+
+    /// Announce new transactions to the peers that do not have them yet, batched on a short timer.
+    pub fn announce_transactions(&self, hashes: Vec<H256>) {
+        self.send(ControlSignal::AnnounceTransactions(hashes));
+    }
+
+    /// Say goodbye to every peer, close the sockets and stop the server thread.
+    pub fn shutdown(&self) {
+        self.send(ControlSignal::Shutdown);
+    }
+
+    /// Send a control signal to the server thread. Returns false if the server has stopped.
+    fn send(&self, signal: ControlSignal) -> bool {
+        if self.control_chan.send(signal).is_err() {
+            warn!("P2P server stopped, dropping control signal");
+            return false;
+        }
+        true
+    }
+}
+
+/// Queue a message for each peer with `write`, recording where it failed.
+fn deliver<'a, F>(peers: impl Iterator<Item = &'a peer::Handle>, write: F) -> Delivery
+where
+    F: Fn(&peer::Handle) -> Result<(), WriteError>,
+{
+    let mut delivery = Delivery::default();
+    for peer in peers {
+        match write(peer) {
+            Ok(()) => delivery.delivered += 1,
+            Err(e) => delivery.failed.push((peer.addr(), e)),
+        }
+    }
+    delivery
+}
+
+/// Announce the blocks and transactions the peer is not known to have. Fails if either
+/// announcement could not be queued.
+fn announce_to(
+    peer: &peer::Handle,
+    blocks: &[H256],
+    transactions: &[H256],
+) -> Result<(), WriteError> {
+    let mut result = Ok(());
+    let new_blocks = unknown_to_peer(peer, blocks);
+    if !new_blocks.is_empty() {
+        result = peer.write(message::Message::NewBlockHashes(new_blocks));
+    }
+    let new_transactions = unknown_to_peer(peer, transactions);
+    if !new_transactions.is_empty() {
+        result = result.and(peer.write(message::Message::NewTransactionHashes(new_transactions)));
+    }
+    result
+}
+
+/// Log which peers an announcement reached.
+fn log_announcement(blocks: usize, transactions: usize, delivery: &Delivery) {
+    debug!(
+        "Announced {} blocks and {} transactions to {} peers",
+        blocks, transactions, delivery.delivered
+    );
+    for (addr, e) in delivery.failed.iter() {
+        debug!("Cannot announce to peer {}: {}", addr, e);
+    }
+}
+
+/// Pick the hashes the peer is not known to have, and remember that it has them from now on.
+fn unknown_to_peer(peer: &peer::Handle, hashes: &[H256]) -> Vec<H256> {
+    let mut unknown: Vec<H256> = Vec::new();
+    for hash in hashes {
+        if !peer.knows(hash) {
+            peer.mark_known(hash);
+            unknown.push(*hash);
+        }
+    }
+    unknown
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
-    BroadcastMessage(message::Message),
+    BroadcastMessage(message::Message, cbchannel::Sender<Delivery>),
+    PingAll(cbchannel::Sender<Delivery>),
+    ListPeers(cbchannel::Sender<Vec<peer::Info>>),
+    Disconnect(std::net::SocketAddr, cbchannel::Sender<bool>),
+    Ban(IpAddr, Duration, cbchannel::Sender<usize>),
+    AnnounceBlocks(Vec<H256>),
+    AnnounceTransactions(Vec<H256>),
+    Shutdown,
 }
 
 struct ConnectRequest {
-    addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use message::Message;
+
+    /// A server context with `count` plaintext peers over loopback sockets, with the remote ends
+    /// of the sockets, which must stay open
+    fn context_with_peers(count: usize) -> (Context, Vec<std::net::TcpStream>) {
+        let (msg_sink, _) = cbchannel::unbounded();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (mut ctx, _) = new(addr, msg_sink, Security::Plaintext).unwrap();
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        let mut remotes = vec![];
+        for _ in 0..count {
+            let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            remotes.push(listener.accept().unwrap().0);
+            let stream = net::TcpStream::from_stream(stream).unwrap();
+            ctx.register(stream, peer::Direction::Outgoing).unwrap();
+        }
+        (ctx, remotes)
+    }
+
+    /// The messages queued for a peer since the last call, leaving out its `Hello`
+    fn queued(ctx: &Context, peer_id: usize) -> Vec<Message> {
+        let peer = &ctx.peers[peer_id];
+        let mut messages = vec![];
+        while let Ok(frame) = peer.writer.queue.try_recv() {
+            match peer.handle.decode(&frame).unwrap() {
+                Message::Hello(_) => {}
+                msg => messages.push(msg),
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn announcements_are_batched() {
+        let (mut ctx, _remotes) = context_with_peers(2);
+        let blocks = vec![generate_random_hash(), generate_random_hash()];
+        let transaction = generate_random_hash();
+        ctx.process_control(ControlSignal::AnnounceBlocks(vec![blocks[0]]))
+            .unwrap();
+        let deadline = ctx.announce_deadline.unwrap();
+        ctx.process_control(ControlSignal::AnnounceBlocks(vec![blocks[1]]))
+            .unwrap();
+        ctx.process_control(ControlSignal::AnnounceTransactions(vec![transaction]))
+            .unwrap();
+        // the later hashes wait for the first deadline
+        assert_eq!(ctx.announce_deadline, Some(deadline));
+        for peer_id in ctx.peer_list.clone() {
+            assert!(queued(&ctx, peer_id).is_empty());
+        }
+
+        ctx.announce();
+        assert!(ctx.announce_deadline.is_none());
+        for peer_id in ctx.peer_list.clone() {
+            match &queued(&ctx, peer_id)[..] {
+                [Message::NewBlockHashes(announced), Message::NewTransactionHashes(transactions)] =>
+                {
+                    assert_eq!(announced, &blocks);
+                    assert_eq!(transactions, &vec![transaction]);
+                }
+                other => panic!("unexpected messages {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn known_inventory_is_not_announced() {
+        let (mut ctx, _remotes) = context_with_peers(2);
+        let (source, other) = (ctx.peer_list[0], ctx.peer_list[1]);
+        let hash = generate_random_hash();
+        // the block came from the first peer
+        ctx.peers[source].handle.mark_known(&hash);
+        ctx.process_control(ControlSignal::AnnounceBlocks(vec![hash]))
+            .unwrap();
+        ctx.announce();
+        assert!(queued(&ctx, source).is_empty());
+        match &queued(&ctx, other)[..] {
+            [Message::NewBlockHashes(announced)] => assert_eq!(announced, &vec![hash]),
+            other => panic!("unexpected messages {:?}", other),
+        }
+
+        // neither hears about it again
+        ctx.process_control(ControlSignal::AnnounceBlocks(vec![hash]))
+            .unwrap();
+        ctx.announce();
+        assert!(queued(&ctx, source).is_empty());
+        assert!(queued(&ctx, other).is_empty());
+    }
+
+    #[test]
+    fn misbehaving_peers_are_banned() {
+        let (mut ctx, mut remotes) = context_with_peers(1);
+        // a second peer on another address
+        let listener = std::net::TcpListener::bind("127.0.0.2:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        remotes.push(listener.accept().unwrap().0);
+        ctx.connect(stream).unwrap();
+        let (bad, good) = (ctx.peer_list[0], ctx.peer_list[1]);
+        let bad_ip = ctx.peers[bad].addr.ip();
+
+        // scores add up, and the peer is banned once they reach the threshold
+        ctx.peers[bad].handle.misbehaved(BAN_THRESHOLD / 2, "test");
+        ctx.check_peers();
+        assert_eq!(ctx.peer_list.len(), 2);
+        ctx.peers[bad].handle.misbehaved(BAN_THRESHOLD / 2, "test");
+        ctx.check_peers();
+        assert_eq!(ctx.peer_list, vec![good]);
+        assert!(ctx.is_banned(&bad_ip));
+
+        // and cannot be dialed again
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let error = ctx.connect(stream).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(ctx.peer_list, vec![good]);
+    }
+
+    #[test]
+    fn broadcast_reports_delivery() {
+        let (mut ctx, _remotes) = context_with_peers(2);
+        let (sender, receiver) = cbchannel::unbounded();
+        ctx.process_control(ControlSignal::BroadcastMessage(Message::Ping(7), sender))
+            .unwrap();
+        let delivery = receiver.recv().unwrap();
+        assert_eq!(delivery.delivered, 2);
+        assert!(delivery.failed.is_empty());
+        for peer_id in ctx.peer_list.clone() {
+            match queued(&ctx, peer_id).as_slice() {
+                [Message::Ping(7)] => {}
+                other => panic!("unexpected messages {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn stopped_server() {
+        let (msg_sink, _) = cbchannel::unbounded();
+        let (ctx, handle) = new(
+            "127.0.0.1:0".parse().unwrap(),
+            msg_sink,
+            Security::Plaintext,
+        )
+        .unwrap();
+        drop(ctx);
+        assert_eq!(handle.peers().err(), Some(Stopped));
+        assert_eq!(handle.broadcast(Message::Ping(1)).err(), Some(Stopped));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = handle
+            .connect(listener.local_addr().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), Stopped.to_string());
+    }
+}
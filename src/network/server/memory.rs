@@ -0,0 +1,108 @@
+//! A server backend moving frames over in-process channels instead of TCP sockets.
+//!
+//! It answers the same `Handle` calls as the TCP event loop, but never spawns a thread: whoever
+//! owns the `Context` calls `poll` to process the pending control signals, and drains the
+//! peers' outgoing queues (see `peer::new_in_memory`) to deliver their frames. Calls waiting for
+//! an answer, like `Handle::ping`, must come from another thread than the one polling.
+
+use super::{announce_to, deliver, log_announcement, ControlSignal, Handle};
+use crate::network::message::Message;
+use crate::network::peer;
+use log::{debug, trace};
+use mio_extras::channel;
+
+pub struct Context {
+    peers: Vec<peer::Handle>,
+    control_chan: channel::Receiver<ControlSignal>,
+    shut_down: bool,
+}
+
+pub fn new() -> (Context, Handle) {
+    let (control_signal_sender, control_signal_receiver) = channel::channel();
+    let handle = Handle {
+        control_chan: control_signal_sender,
+    };
+    let ctx = Context {
+        peers: vec![],
+        control_chan: control_signal_receiver,
+        shut_down: false,
+    };
+    (ctx, handle)
+}
+
+impl Context {
+    /// Add a peer connected by some in-memory transport.
+    pub fn add_peer(&mut self, peer: peer::Handle) {
+        self.peers.push(peer);
+    }
+
+    /// Process every pending control signal. Announcements go out right away rather than on
+    /// a timer, so that simulations stay deterministic.
+    pub fn poll(&mut self) {
+        while let Ok(signal) = self.control_chan.try_recv() {
+            if self.shut_down {
+                continue;
+            }
+            match signal {
+                ControlSignal::ConnectNewPeer(req) => {
+                    trace!("Processing ConnectNewPeer command");
+                    let result = Err(std::io::Error::other(
+                        "in-memory server cannot take TCP connections",
+                    ));
+                    req.result_chan.send(result).unwrap();
+                }
+                ControlSignal::BroadcastMessage(msg, result_chan) => {
+                    trace!("Processing BroadcastMessage command");
+                    let delivery = deliver(self.peers.iter(), |peer| peer.write(msg.clone()));
+                    result_chan.send(delivery).unwrap();
+                }
+                ControlSignal::PingAll(result_chan) => {
+                    trace!("Processing PingAll command");
+                    result_chan
+                        .send(deliver(self.peers.iter(), |peer| peer.ping()))
+                        .unwrap();
+                }
+                ControlSignal::Disconnect(addr, result_chan) => {
+                    trace!("Processing Disconnect command");
+                    let count = self.peers.len();
+                    self.peers.retain(|peer| peer.addr() != addr);
+                    result_chan.send(self.peers.len() < count).unwrap();
+                }
+                ControlSignal::Ban(ip, _, result_chan) => {
+                    trace!("Processing Ban command");
+                    let count = self.peers.len();
+                    self.peers.retain(|peer| peer.addr().ip() != ip);
+                    result_chan.send(count - self.peers.len()).unwrap();
+                }
+                ControlSignal::ListPeers(result_chan) => {
+                    trace!("Processing ListPeers command");
+                    let peers = self.peers.iter().map(|peer| peer.info()).collect();
+                    result_chan.send(peers).unwrap();
+                }
+                ControlSignal::AnnounceBlocks(hashes) => {
+                    trace!("Processing AnnounceBlocks command");
+                    let delivery =
+                        deliver(self.peers.iter(), |peer| announce_to(peer, &hashes, &[]));
+                    log_announcement(hashes.len(), 0, &delivery);
+                }
+                ControlSignal::AnnounceTransactions(hashes) => {
+                    trace!("Processing AnnounceTransactions command");
+                    let delivery =
+                        deliver(self.peers.iter(), |peer| announce_to(peer, &[], &hashes));
+                    log_announcement(0, hashes.len(), &delivery);
+                }
+                ControlSignal::Shutdown => {
+                    trace!("Processing Shutdown command");
+                    for peer in &self.peers {
+                        let goodbye = Message::Goodbye("node shutting down".to_string());
+                        if let Err(e) = peer.write(goodbye) {
+                            debug!("Cannot say goodbye to peer {}: {}", peer.addr(), e);
+                        }
+                    }
+                    self.peers.clear();
+                    self.shut_down = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! A deterministic multi-node network simulator.
+//!
+//! Nodes run the real worker message handling on top of the in-memory server backend, while
+//! the simulator moves their frames over links with latency, bandwidth, packet loss and
+//! partitions. Time is a virtual clock in milliseconds and every random choice comes from one
+//! seeded generator, so a run is fully determined by its seed and configuration.
+
+use super::peer;
+use super::server::{self, memory};
+use super::sync;
+use super::worker;
+use crate::block::{Block, Content, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::merkle::MerkleTree;
+use crate::mempool::Mempool;
+use crate::transaction::{self, SignedTransaction, Transaction};
+use crate::verifier::{Verifier, CACHE_SIZE};
+use crossbeam::channel as cbchannel;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// The properties of a link between two nodes, the same in both directions
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConfig {
+    pub latency_ms: u64,
+    /// Bytes per second
+    pub bandwidth: u64,
+    /// Probability that a message is lost
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            latency_ms: 50,
+            bandwidth: 1_000_000,
+            loss: 0.0,
+        }
+    }
+}
+
+/// What happened during a simulation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub blocks_mined: usize,
+    /// Mined blocks that did not end up in the longest chain of node 0
+    pub stale_blocks: usize,
+    /// Number of times a node switched to a chain not extending its previous tip
+    pub reorgs: usize,
+    /// The largest number of blocks a node abandoned in a reorg
+    pub max_reorg_depth: usize,
+    pub messages_sent: usize,
+    pub messages_dropped: usize,
+}
+
+impl Stats {
+    /// The share of mined blocks that ended up off the longest chain
+    pub fn fork_rate(&self) -> f64 {
+        if self.blocks_mined == 0 {
+            return 0.0;
+        }
+        self.stale_blocks as f64 / self.blocks_mined as f64
+    }
+}
+
+struct Link {
+    remote: usize,
+    /// The index of the link on the remote node that leads back to us
+    remote_link: usize,
+    handle: peer::Handle,
+    outgoing: peer::InMemoryQueue,
+    config: LinkConfig,
+    /// When the link is done sending the frames already queued on it
+    busy_until: u64,
+}
+
+struct Node {
+    blockchain: Arc<Mutex<Blockchain>>,
+    worker: worker::Context,
+    server: memory::Context,
+    server_handle: server::Handle,
+    links: Vec<Link>,
+    /// The tip the node had after the last event, to detect reorgs
+    tip: H256,
+    /// Nodes only talk to nodes of the same partition group
+    group: usize,
+}
+
+enum Event {
+    Deliver {
+        node: usize,
+        link: usize,
+        frame: Vec<u8>,
+    },
+    Mine {
+        node: usize,
+    },
+}
+
+struct Scheduled {
+    time: u64,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // reversed, so that the max-heap pops the earliest event first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+pub struct Simulator {
+    nodes: Vec<Node>,
+    queue: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    now: u64,
+    rng: StdRng,
+    /// Mean time between blocks over the whole network, while mining
+    block_interval_ms: Option<u64>,
+    stats: Stats,
+}
+
+fn node_addr(node: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, (node >> 8) as u8, node as u8], 6000))
+}
+
+impl Simulator {
+    /// Create `num_nodes` unconnected nodes sharing the genesis block.
+    pub fn new(num_nodes: usize, seed: u64) -> Self {
+        let nodes = (0..num_nodes)
+            .map(|_| {
+                let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+                let tip = blockchain.lock().unwrap().tip();
+                let (server, server_handle) = memory::new();
+                let (_, sync) = sync::new();
+                // messages are handed to the worker directly, nothing reads this channel
+                let (_, msg_rx) = cbchannel::unbounded();
+                let mempool = Arc::new(Mutex::new(Mempool::new()));
+                let verifier = Arc::new(Verifier::new(CACHE_SIZE, 1));
+                let worker = worker::new(
+                    1,
+                    msg_rx,
+                    &server_handle,
+                    &blockchain,
+                    &mempool,
+                    &verifier,
+                    &sync,
+                );
+                Node {
+                    blockchain,
+                    worker,
+                    server,
+                    server_handle,
+                    links: vec![],
+                    tip,
+                    group: 0,
+                }
+            })
+            .collect();
+        Simulator {
+            nodes,
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            now: 0,
+            rng: StdRng::seed_from_u64(seed),
+            block_interval_ms: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Connect two nodes with a link.
+    pub fn connect(&mut self, a: usize, b: usize, config: LinkConfig) {
+        let (handle_a, outgoing_a) = peer::new_in_memory(node_addr(b), peer::Direction::Outgoing);
+        let (handle_b, outgoing_b) = peer::new_in_memory(node_addr(a), peer::Direction::Incoming);
+        let link_a = self.nodes[a].links.len();
+        let link_b = self.nodes[b].links.len();
+        self.nodes[a].server.add_peer(handle_a.clone());
+        self.nodes[b].server.add_peer(handle_b.clone());
+        self.nodes[a].links.push(Link {
+            remote: b,
+            remote_link: link_b,
+            handle: handle_a,
+            outgoing: outgoing_a,
+            config,
+            busy_until: 0,
+        });
+        self.nodes[b].links.push(Link {
+            remote: a,
+            remote_link: link_a,
+            handle: handle_b,
+            outgoing: outgoing_b,
+            config,
+            busy_until: 0,
+        });
+    }
+
+    /// Connect every pair of nodes.
+    pub fn connect_all(&mut self, config: LinkConfig) {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b, config);
+            }
+        }
+    }
+
+    /// Cut the given nodes off from the others. Messages already in flight still arrive.
+    pub fn partition(&mut self, group: &[usize]) {
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.group = if group.contains(&i) { 1 } else { 0 };
+        }
+    }
+
+    /// Undo a partition.
+    pub fn heal(&mut self) {
+        for node in self.nodes.iter_mut() {
+            node.group = 0;
+        }
+    }
+
+    /// Start mining on every node with equal hash power, one block every `block_interval_ms`
+    /// on average over the whole network.
+    pub fn start_mining(&mut self, block_interval_ms: u64) {
+        let start = self.block_interval_ms.is_none();
+        self.block_interval_ms = Some(block_interval_ms);
+        if start {
+            for node in 0..self.nodes.len() {
+                self.schedule_mining(node);
+            }
+        }
+    }
+
+    pub fn stop_mining(&mut self) {
+        self.block_interval_ms = None;
+    }
+
+    /// Run every event up to the given virtual time.
+    pub fn run_until(&mut self, time_ms: u64) {
+        while self.queue.peek().is_some_and(|x| x.time <= time_ms) {
+            let scheduled = self.queue.pop().unwrap();
+            self.now = scheduled.time;
+            self.process(scheduled.event);
+        }
+        self.now = time_ms;
+    }
+
+    /// Run until no message is in flight. Mining must be stopped.
+    pub fn run_until_idle(&mut self) {
+        assert!(self.block_interval_ms.is_none(), "mining is still running");
+        while let Some(scheduled) = self.queue.pop() {
+            self.now = scheduled.time;
+            self.process(scheduled.event);
+        }
+    }
+
+    /// The current virtual time in milliseconds
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn tip(&self, node: usize) -> H256 {
+        self.nodes[node].blockchain.lock().unwrap().tip()
+    }
+
+    pub fn chain_length(&self, node: usize) -> u32 {
+        self.nodes[node].blockchain.lock().unwrap().chainLength
+    }
+
+    /// Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        let tip = self.tip(0);
+        (1..self.nodes.len()).all(|node| self.tip(node) == tip)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        let in_longest_chain = self.chain_length(0) as usize - 1;
+        stats.stale_blocks = stats.blocks_mined.saturating_sub(in_longest_chain);
+        stats
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Scheduled { time, seq, event });
+    }
+
+    fn schedule_mining(&mut self, node: usize) {
+        let interval = match self.block_interval_ms {
+            Some(interval) => interval,
+            None => return,
+        };
+        // block discovery is a Poisson process
+        let mean = (interval * self.nodes.len() as u64) as f64;
+        let uniform: f64 = self.rng.gen();
+        let delay = -(1.0 - uniform).ln() * mean;
+        self.schedule(self.now + delay as u64, Event::Mine { node });
+    }
+
+    fn process(&mut self, event: Event) {
+        match event {
+            Event::Deliver { node, link, frame } => {
+                let handle = self.nodes[node].links[link].handle.clone();
+                self.nodes[node].worker.process_message(&frame, handle);
+                self.flush(node);
+                self.check_tip(node);
+            }
+            Event::Mine { node } => {
+                if self.block_interval_ms.is_none() {
+                    return;
+                }
+                self.mine(node);
+                self.flush(node);
+                self.check_tip(node);
+                self.schedule_mining(node);
+            }
+        }
+    }
+
+    fn mine(&mut self, node: usize) {
+        let (parent, difficulty, median_time) = {
+            let blockchain = self.nodes[node].blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            (
+                tip,
+                blockchain.blockMap[&tip].header.difficulty,
+                blockchain.median_time_past(&tip),
+            )
+        };
+        // a transaction signed by a fresh key, as peers verify signatures
+        let seed: [u8; 32] = self.rng.gen();
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let output: [u8; 20] = self.rng.gen();
+        let raw = Transaction {
+            input: transaction::convertPubKeyToH160(key.public_key()),
+            output: output.into(),
+            amount: 1.0,
+            lock_time: 0,
+            nonce: 0,
+        };
+        let data = vec![SignedTransaction {
+            input: raw.input,
+            output: raw.output,
+            amount: raw.amount,
+            lock_time: raw.lock_time,
+            nonce: raw.nonce,
+            pub_key: transaction::convertPubKeyToH256(key.public_key()),
+            signature: transaction::convertSigToH256(&transaction::sign(&raw, &key)),
+            multisig: None,
+            script: None,
+        }];
+        let merkle_root = MerkleTree::new(&data).root();
+        let mut header = Header {
+            parent,
+            nonce: self.rng.gen(),
+            difficulty,
+            timestamp: (self.now as u128).max(median_time + 1),
+            merkle_root,
+        };
+        while header.hash() > difficulty {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        let block = Block {
+            header,
+            content: Content { data },
+        };
+        self.nodes[node].blockchain.lock().unwrap().insert(&block);
+        self.nodes[node]
+            .server_handle
+            .announce_blocks(vec![block.hash()]);
+        self.stats.blocks_mined += 1;
+    }
+
+    /// Process the node's pending server signals and put its outgoing frames on the links.
+    fn flush(&mut self, node: usize) {
+        self.nodes[node].server.poll();
+        for link in 0..self.nodes[node].links.len() {
+            while let Ok(frame) = self.nodes[node].links[link].outgoing.try_recv() {
+                self.transmit(node, link, frame);
+            }
+        }
+    }
+
+    fn transmit(&mut self, node: usize, link: usize, frame: Vec<u8>) {
+        self.stats.messages_sent += 1;
+        let lost: f64 = self.rng.gen();
+        let remote = self.nodes[node].links[link].remote;
+        let config = self.nodes[node].links[link].config;
+        if self.nodes[node].group != self.nodes[remote].group || lost < config.loss {
+            self.stats.messages_dropped += 1;
+            return;
+        }
+        let link = &mut self.nodes[node].links[link];
+        let start = link.busy_until.max(self.now);
+        link.busy_until = start + frame.len() as u64 * 1000 / config.bandwidth;
+        let arrival = link.busy_until + config.latency_ms;
+        let event = Event::Deliver {
+            node: remote,
+            link: link.remote_link,
+            frame,
+        };
+        self.schedule(arrival, event);
+    }
+
+    fn check_tip(&mut self, node: usize) {
+        let blockchain = self.nodes[node].blockchain.lock().unwrap();
+        let old_tip = self.nodes[node].tip;
+        let new_tip = blockchain.tip();
+        if new_tip == old_tip {
+            return;
+        }
+        let chain: HashSet<H256> = blockchain
+            .all_blocks_in_longest_chain()
+            .into_iter()
+            .collect();
+        let mut depth = 0;
+        let mut hash = old_tip;
+        while !chain.contains(&hash) {
+            depth += 1;
+            hash = blockchain.blockMap[&hash].header.parent;
+        }
+        drop(blockchain);
+        if depth > 0 {
+            self.stats.reorgs += 1;
+            self.stats.max_reorg_depth = self.stats.max_reorg_depth.max(depth);
+        }
+        self.nodes[node].tip = new_tip;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring_with_chords(sim: &mut Simulator, num_nodes: usize, config: LinkConfig) {
+        for i in 0..num_nodes {
+            sim.connect(i, (i + 1) % num_nodes, config);
+        }
+        for i in 0..num_nodes / 2 {
+            sim.connect(i, i + num_nodes / 2, config);
+        }
+    }
+
+    #[test]
+    fn converges() {
+        let mut sim = Simulator::new(8, 1);
+        ring_with_chords(&mut sim, 8, LinkConfig::default());
+        sim.start_mining(2000);
+        sim.run_until(60_000);
+        sim.stop_mining();
+        sim.run_until_idle();
+        assert!(sim.converged());
+        let stats = sim.stats();
+        assert!(stats.blocks_mined > 10);
+        assert_eq!(
+            sim.chain_length(3) as usize,
+            stats.blocks_mined - stats.stale_blocks + 1
+        );
+        assert!(stats.fork_rate() < 0.5);
+    }
+
+    #[test]
+    fn partition_heals_with_reorg() {
+        let mut sim = Simulator::new(6, 2);
+        sim.connect_all(LinkConfig::default());
+        sim.partition(&[0, 1, 2]);
+        sim.start_mining(1000);
+        sim.run_until(30_000);
+        assert!(!sim.converged());
+        sim.heal();
+        sim.run_until(60_000);
+        sim.stop_mining();
+        sim.run_until_idle();
+        assert!(sim.converged());
+        let stats = sim.stats();
+        assert!(stats.messages_dropped > 0);
+        assert!(stats.max_reorg_depth > 1);
+    }
+
+    #[test]
+    fn deterministic() {
+        let run = |seed: u64| {
+            let mut sim = Simulator::new(5, seed);
+            let config = LinkConfig {
+                latency_ms: 200,
+                bandwidth: 50_000,
+                loss: 0.02,
+            };
+            ring_with_chords(&mut sim, 5, config);
+            sim.start_mining(500);
+            sim.run_until(20_000);
+            sim.stop_mining();
+            sim.run_until_idle();
+            let tips: Vec<H256> = (0..5).map(|node| sim.tip(node)).collect();
+            (sim.stats(), tips)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7).1, run(8).1);
+    }
+}
@@ -0,0 +1,421 @@
+use super::message::Message;
+use super::peer::{self, WriteError};
+use super::request::{Reply, RequestError};
+use crate::block::Header;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H256};
+use log::{debug, info, warn};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The maximum number of headers sent in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// The maximum number of blocks asked for in one `GetBlock` message during sync
+const BLOCKS_PER_REQUEST: usize = 16;
+/// The maximum number of block bodies requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 64;
+/// How long a peer has to deliver a requested block before we ask someone else
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many timeouts a peer may cause before it is no longer used for sync
+const MAX_PEER_TIMEOUTS: u32 = 3;
+/// How often the timeout checker wakes up
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Why a batch of headers was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeadersError {
+    /// The first header does not connect to a block or header we know. This happens to honest
+    /// peers too, e.g. when they answer a locator that was outdated by a reorg.
+    UnknownParent(H256),
+    /// The headers are not a valid chain
+    Invalid(String),
+}
+
+impl std::fmt::Display for HeadersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeadersError::UnknownParent(hash) => write!(f, "header {} has unknown parent", hash),
+            HeadersError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// A `GetBlock` sent to a sync peer
+struct Request {
+    peer: SocketAddr,
+    hashes: Vec<H256>,
+    reply: Reply,
+}
+
+struct State {
+    /// Headers with valid proof of work whose block bodies we do not have yet, with the order in
+    /// which they were accepted
+    headers: HashMap<H256, (u64, Header)>,
+    next_sequence: u64,
+    /// Block bodies waiting to be requested, in chain order
+    queue: VecDeque<H256>,
+    /// Block bodies requested and not yet received, with the peer asked
+    in_flight: HashMap<H256, SocketAddr>,
+    /// Requests waiting for their reply or their timeout
+    requests: Vec<Request>,
+    /// The peer that last timed out on a block, so the re-request goes elsewhere
+    timed_out_by: HashMap<H256, SocketAddr>,
+    /// Peers that answered our `GetHeaders` and are used to download bodies
+    peers: HashMap<SocketAddr, peer::Handle>,
+    timeouts: HashMap<SocketAddr, u32>,
+    running: bool,
+}
+
+pub struct Context {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    state: Arc<Mutex<State>>,
+}
+
+pub fn new() -> (Context, Handle) {
+    let state = Arc::new(Mutex::new(State {
+        headers: HashMap::new(),
+        next_sequence: 0,
+        queue: VecDeque::new(),
+        in_flight: HashMap::new(),
+        requests: Vec::new(),
+        timed_out_by: HashMap::new(),
+        peers: HashMap::new(),
+        timeouts: HashMap::new(),
+        running: true,
+    }));
+    let ctx = Context {
+        state: Arc::clone(&state),
+    };
+    let handle = Handle { state };
+    (ctx, handle)
+}
+
+impl Context {
+    /// Start the thread re-requesting block bodies that timed out.
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
+            .name("sync".to_string())
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                let mut state = self.state.lock().unwrap();
+                if !state.running {
+                    return;
+                }
+                state.check_timeouts();
+                state.dispatch();
+            })
+            .unwrap();
+        info!("Block sync initialized");
+        handle
+    }
+}
+
+impl Handle {
+    /// Validate a batch of headers received from `peer` and schedule the download of the new
+    /// blocks. Returns the number of new headers, or why the batch was rejected.
+    pub fn accept_headers(
+        &self,
+        headers: &[Header],
+        peer: &peer::Handle,
+        blockchain: &Blockchain,
+    ) -> Result<usize, HeadersError> {
+        let mut state = self.state.lock().unwrap();
+        // the whole batch is checked before any of it is scheduled
+        let mut new_headers: Vec<(H256, &Header)> = Vec::new();
+        let mut previous: Option<(H256, H256)> = None;
+        for (i, header) in headers.iter().enumerate() {
+            let hash = header.hash();
+            let parent_difficulty = match previous {
+                Some((parent, difficulty)) if parent == header.parent => difficulty,
+                _ => match blockchain.blockMap.get(&header.parent) {
+                    Some(parent) => parent.header.difficulty,
+                    None => match state.headers.get(&header.parent) {
+                        Some((_, parent)) => parent.difficulty,
+                        None if i == 0 => return Err(HeadersError::UnknownParent(hash)),
+                        None => {
+                            return Err(HeadersError::Invalid(format!(
+                                "header {} does not follow the previous one",
+                                hash
+                            )))
+                        }
+                    },
+                },
+            };
+            if header.difficulty != parent_difficulty {
+                return Err(HeadersError::Invalid(format!(
+                    "header {} has wrong difficulty",
+                    hash
+                )));
+            }
+            if hash > header.difficulty {
+                return Err(HeadersError::Invalid(format!(
+                    "header {} has invalid proof of work",
+                    hash
+                )));
+            }
+            previous = Some((hash, header.difficulty));
+            if blockchain.blockMap.contains_key(&hash)
+                || state.headers.contains_key(&hash)
+                || new_headers.iter().any(|(x, _)| *x == hash)
+            {
+                continue;
+            }
+            new_headers.push((hash, header));
+        }
+        let mut new_hashes: Vec<H256> = Vec::new();
+        for (hash, header) in new_headers {
+            let sequence = state.next_sequence;
+            state.next_sequence += 1;
+            state.headers.insert(hash, (sequence, header.clone()));
+            new_hashes.push(hash);
+        }
+        state.queue.extend(new_hashes.iter());
+        state.peers.insert(peer.addr(), peer.clone());
+        state.dispatch();
+        Ok(new_hashes.len())
+    }
+
+    /// Record that a block arrived, so it is no longer waited for.
+    pub fn block_received(&self, hash: &H256) {
+        let mut state = self.state.lock().unwrap();
+        state.headers.remove(hash);
+        state.in_flight.remove(hash);
+        state.timed_out_by.remove(hash);
+    }
+
+    /// Put back a downloaded block that failed validation, to be asked from another peer than
+    /// `peer`.
+    pub fn block_rejected(&self, hash: &H256, peer: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        if !state.headers.contains_key(hash) {
+            return;
+        }
+        state.in_flight.remove(hash);
+        state.timed_out_by.insert(*hash, peer);
+        if !state.queue.contains(hash) {
+            state.queue.push_front(*hash);
+        }
+    }
+
+    /// Whether the block with this hash is already scheduled for download
+    pub fn is_scheduled(&self, hash: &H256) -> bool {
+        self.state.lock().unwrap().headers.contains_key(hash)
+    }
+
+    /// Stop the timeout thread.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().running = false;
+    }
+
+    /// Request more block bodies from the sync peers, if any are waiting.
+    pub fn dispatch(&self) {
+        self.state.lock().unwrap().dispatch();
+    }
+}
+
+impl State {
+    fn in_flight_count(&self, peer: &SocketAddr) -> usize {
+        self.in_flight.values().filter(|x| *x == peer).count()
+    }
+
+    fn dispatch(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        let mut peers: Vec<SocketAddr> = self.peers.keys().cloned().collect();
+        peers.sort_by_key(|addr| self.in_flight_count(addr));
+        let num_peers = peers.len();
+        for addr in peers {
+            let mut budget =
+                MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(self.in_flight_count(&addr));
+            let mut skipped: Vec<H256> = Vec::new();
+            while budget > 0 && !self.queue.is_empty() {
+                let mut batch: Vec<H256> = Vec::new();
+                while batch.len() < BLOCKS_PER_REQUEST.min(budget) {
+                    let hash = match self.queue.pop_front() {
+                        Some(hash) => hash,
+                        None => break,
+                    };
+                    if !self.headers.contains_key(&hash) {
+                        // arrived in the meantime, e.g. through gossip
+                        continue;
+                    }
+                    if num_peers > 1 && self.timed_out_by.get(&hash) == Some(&addr) {
+                        skipped.push(hash);
+                        continue;
+                    }
+                    batch.push(hash);
+                }
+                if batch.is_empty() {
+                    break;
+                }
+                debug!("Requesting {} blocks from {}", batch.len(), addr);
+                let request = Message::GetBlock(batch.clone());
+                let reply = match self.peers[&addr].request(request, BLOCK_DOWNLOAD_TIMEOUT) {
+                    Ok(reply) => reply,
+                    Err(RequestError::Write(WriteError::Disconnected)) => {
+                        debug!("Peer {} is gone, no longer syncing from it", addr);
+                        self.peers.remove(&addr);
+                        self.timeouts.remove(&addr);
+                        skipped.extend(batch);
+                        break;
+                    }
+                    Err(e) => {
+                        // leave the blocks to the other peers
+                        debug!("Cannot request blocks from {}: {}", addr, e);
+                        skipped.extend(batch);
+                        break;
+                    }
+                };
+                for hash in batch.iter() {
+                    self.in_flight.insert(*hash, addr);
+                }
+                budget -= batch.len();
+                self.requests.push(Request {
+                    peer: addr,
+                    hashes: batch,
+                    reply,
+                });
+            }
+            for hash in skipped.into_iter().rev() {
+                self.queue.push_front(hash);
+            }
+        }
+    }
+
+    fn check_timeouts(&mut self) {
+        let (expired, waiting): (Vec<Request>, Vec<Request>) =
+            self.requests.drain(..).partition(|x| x.reply.is_expired());
+        self.requests = waiting;
+        let mut late: Vec<H256> = Vec::new();
+        for request in expired {
+            let missing: Vec<H256> = request
+                .hashes
+                .iter()
+                .cloned()
+                .filter(|hash| self.in_flight.get(hash) == Some(&request.peer))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            for hash in missing.iter() {
+                self.in_flight.remove(hash);
+                self.timed_out_by.insert(*hash, request.peer);
+            }
+            late.extend(missing);
+            // a peer that sent part of the batch is slow, not unresponsive
+            if request.reply.try_get().is_some() {
+                continue;
+            }
+            let count = self.timeouts.entry(request.peer).or_insert(0);
+            *count += 1;
+            if *count >= MAX_PEER_TIMEOUTS && self.peers.contains_key(&request.peer) {
+                warn!(
+                    "Peer {} keeps timing out, no longer syncing from it",
+                    request.peer
+                );
+                self.peers.remove(&request.peer);
+            }
+        }
+        // keep chain order when putting them back at the front of the queue
+        late.sort_by_key(|hash| self.headers.get(hash).map(|(sequence, _)| *sequence));
+        for hash in late.into_iter().rev() {
+            self.queue.push_front(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    /// A header on `parent` with the genesis difficulty and a valid proof of work
+    fn mined_header(blockchain: &Blockchain, parent: &H256) -> Header {
+        let difficulty = blockchain.blockMap[&blockchain.tip()].header.difficulty;
+        loop {
+            let mut header = generate_random_block(parent).header;
+            header.difficulty = difficulty;
+            if header.hash() <= difficulty {
+                return header;
+            }
+        }
+    }
+
+    #[test]
+    fn headers_that_do_not_connect() {
+        let blockchain = Blockchain::new();
+        let (_, handle) = new();
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let (peer, _queue) = peer::new_in_memory(addr, peer::Direction::Outgoing);
+        let first = mined_header(&blockchain, &blockchain.tip());
+        let second = mined_header(&blockchain, &first.hash());
+        let stranger = mined_header(&blockchain, &H256::from([7; 32]));
+
+        // a batch starting from a block we do not know is not the peer's fault
+        assert_eq!(
+            handle.accept_headers(std::slice::from_ref(&stranger), &peer, &blockchain),
+            Err(HeadersError::UnknownParent(stranger.hash()))
+        );
+        // a gap inside the batch is, and schedules none of it
+        match handle.accept_headers(&[first.clone(), stranger], &peer, &blockchain) {
+            Err(HeadersError::Invalid(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!handle.is_scheduled(&first.hash()));
+
+        assert_eq!(
+            handle.accept_headers(&[first.clone(), second], &peer, &blockchain),
+            Ok(2)
+        );
+        assert!(handle.is_scheduled(&first.hash()));
+    }
+
+    #[test]
+    fn rejected_blocks_and_gone_peers() {
+        let blockchain = Blockchain::new();
+        let (_, handle) = new();
+        let (first_peer, _first_queue) =
+            peer::new_in_memory("127.0.0.1:6000".parse().unwrap(), peer::Direction::Outgoing);
+        let (second_peer, second_queue) =
+            peer::new_in_memory("127.0.0.2:6000".parse().unwrap(), peer::Direction::Outgoing);
+        let header = mined_header(&blockchain, &blockchain.tip());
+        let hash = header.hash();
+        let headers = [header];
+        assert_eq!(
+            handle.accept_headers(&headers, &first_peer, &blockchain),
+            Ok(1)
+        );
+        assert_eq!(
+            handle.accept_headers(&headers, &second_peer, &blockchain),
+            Ok(0)
+        );
+        let in_flight =
+            |handle: &Handle| handle.state.lock().unwrap().in_flight.get(&hash).cloned();
+        assert_eq!(in_flight(&handle), Some(first_peer.addr()));
+
+        // an invalid body is asked from another peer
+        handle.block_rejected(&hash, first_peer.addr());
+        handle.dispatch();
+        assert!(handle.is_scheduled(&hash));
+        assert_eq!(in_flight(&handle), Some(second_peer.addr()));
+
+        // a disconnected peer is no longer used
+        drop(second_queue);
+        handle.block_rejected(&hash, first_peer.addr());
+        handle.dispatch();
+        // the only peer left is asked again
+        handle.dispatch();
+        let state = handle.state.lock().unwrap();
+        assert!(!state.peers.contains_key(&second_peer.addr()));
+        assert_eq!(state.in_flight.get(&hash), Some(&first_peer.addr()));
+    }
+}
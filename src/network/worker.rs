@@ -1,17 +1,23 @@
-use super::message::Message;
+use super::compact::{CompactBlock, PartialBlock, PendingBlocks};
+use super::message::{Message, MAX_BLOCKS_PER_REQUEST, MAX_TRANSACTIONS_PER_REQUEST};
+use super::orphan::OrphanPool;
 use super::peer;
+use super::ratelimit::Rejection;
+use super::sync::{self, HeadersError, MAX_HEADERS};
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, info, warn};
 
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::crypto::hash::H256;
 use crate::crypto::hash::Hashable;
-use std::collections::HashMap;
 use crate::blockchain::Blockchain;
 use crate::block::{Header,Content,Block};
 use crate::transaction::{SignedTransaction};
+use crate::mempool::Mempool;
+use crate::script::Context as ScriptContext;
+use crate::verifier::Verifier;
 
 
 #[derive(Clone)]
@@ -19,149 +25,441 @@ pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>
+    blockchain: Arc<Mutex<Blockchain>>,
+    orphans: Arc<Mutex<OrphanPool>>,
+    mempool: Arc<Mutex<Mempool>>,
+    verifier: Arc<Verifier>,
+    /// Compact blocks waiting for the transactions we asked their peer for
+    compact_blocks: Arc<Mutex<PendingBlocks>>,
+    sync: sync::Handle
 }
 
 pub fn new(
     num_worker: usize,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    verifier: &Arc<Verifier>,
+    sync: &sync::Handle
 ) -> Context {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
-        blockchain: Arc::clone(blockchain)
+        blockchain: Arc::clone(blockchain),
+        orphans: Arc::new(Mutex::new(OrphanPool::new())),
+        mempool: Arc::clone(mempool),
+        verifier: Arc::clone(verifier),
+        compact_blocks: Arc::new(Mutex::new(PendingBlocks::new())),
+        sync: sync.clone()
+    }
+}
+
+/// Queue a message for the peer. Failures are only logged: the peer is gone, or too slow to be
+/// worth more effort.
+fn send(peer: &peer::Handle, msg: Message) {
+    if let Err(e) = peer.write(msg) {
+        debug!("Cannot send to peer {}: {}", peer.addr(), e);
     }
 }
 
 impl Context {
-    pub fn start(self) {
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
+        let mut handles = vec![];
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+            let handle = thread::Builder::new()
+                .name(format!("p2p-worker-{}", i))
+                .spawn(move || {
+                    cloned.worker_loop();
+                    warn!("Worker thread {} exited", i);
+                })
+                .unwrap();
+            handles.push(handle);
         }
+        handles
     }
 
     fn worker_loop(&self) {
-
-        let mut orphanBlocks = HashMap::new();// has parent Hash -> child Block mapping
-
         loop {
-            let msg = self.msg_chan.recv().unwrap();
+            // the queue is drained before the channel reports the server is gone
+            let msg = match self.msg_chan.recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            };
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            self.process_message(&msg, peer);
+        println!("----------------         Chain length {:?}", self.blockchain.lock().unwrap().chainLength);// , self.blockchain.lock().unwrap().blockMap.keys().len());
+        println!("Chain Tip {:?}", self.blockchain.lock().unwrap().tip());
+        }
+    }
 
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+    /// Handle one message received from `peer`.
+    pub fn process_message(&self, msg: &[u8], peer: peer::Handle) {
+        let msg: Message = match peer.decode(msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Cannot decode message from {}: {}", peer.addr(), e);
+                peer.misbehaved(10, "undecodable message");
+                return;
+            }
+        };
+        // replies to our own requests are not charged to the peer
+        if !peer.response_received(&msg) {
+            match peer.allow(&msg) {
+                Ok(()) => {}
+                Err(Rejection::Oversized(reason)) => {
+                    debug!("Dropping message from {}: {}", peer.addr(), reason);
+                    peer.misbehaved(20, &reason);
+                    return;
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+                Err(Rejection::RateLimited(reason)) => {
+                    debug!("Dropping message from {}: {}", peer.addr(), reason);
+                    peer.misbehaved(1, "rate limit exceeded");
+                    return;
                 }
-                Message::NewBlockHashes(blockHashVec) => {
+            }
+        }
 
-                    let mut wantedBlocks: Vec<H256> = Vec::new();
-                    for blockHash in blockHashVec.iter() {
-                        if self.blockchain.lock().unwrap().blockMap.contains_key(blockHash) {
-                            continue;
-                        }else {
-                            wantedBlocks.push(*blockHash);
-                            
-                        }
-                    }
-                    if wantedBlocks.len() > 0 {
-                        peer.write(Message::GetBlock(wantedBlocks));
-                        debug!("want blocks")
+        match msg {
+            Message::Hello(hello) => {
+                debug!("Hello from {}: {:?}", peer.addr(), hello);
+                peer.handshake_received(hello);
+                // catch up with the chain of the peers we connect to, headers first
+                if let peer::Direction::Outgoing = peer.direction() {
+                    let locator = self.blockchain.lock().unwrap().block_locator();
+                    send(&peer, Message::GetHeaders(locator));
+                }
+            }
+            Message::Goodbye(reason) => {
+                info!("Peer {} is disconnecting: {}", peer.addr(), reason);
+            }
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                send(&peer, Message::Pong(nonce));
+            }
+            Message::Pong(nonce) => {
+                match peer.pong_received(nonce) {
+                    Some(rtt) => debug!("Pong from {}: rtt {:?}", peer.addr(), rtt),
+                    None => debug!("Unexpected pong from {}: {}", peer.addr(), nonce),
+                }
+            }
+            Message::NewBlockHashes(blockHashVec) => {
+
+                let mut wantedBlocks: Vec<H256> = Vec::new();
+                for blockHash in blockHashVec.iter() {
+                    peer.mark_known(blockHash);
+                    if self.blockchain.lock().unwrap().blockMap.contains_key(blockHash) {
+                        continue;
+                    }else {
+                        wantedBlocks.push(*blockHash);
+                        
                     }
-                    
-                    
                 }
-                Message::GetBlock(blockHashVec) => {
+                for chunk in wantedBlocks.chunks(MAX_BLOCKS_PER_REQUEST) {
+                    send(&peer, Message::GetCompactBlock(chunk.to_vec()));
+                    debug!("want blocks")
+                }
+                
+                
+            }
+            Message::GetBlock(blockHashVec) => {
 
-                    let mut blockVecToSend: Vec<Block> = Vec::new();
-                    for blockHash in blockHashVec.iter() {
-                        if self.blockchain.lock().unwrap().blockMap.contains_key(blockHash) {
-                            blockVecToSend.push(self.blockchain.lock().unwrap().blockMap[blockHash].clone());
-                        }else {
-                            continue;                            
-                        }
+                // the reply would only be dropped, so spare the blockchain lock
+                if peer.is_congested() {
+                    debug!("Peer {} is congested, ignoring its block request", peer.addr());
+                    return;
+                }
+                let mut blockVecToSend: Vec<Block> = Vec::new();
+                let blockchain = self.blockchain.lock().unwrap();
+                for blockHash in blockHashVec.iter() {
+                    if let Some(block) = blockchain.blockMap.get(blockHash) {
+                        blockVecToSend.push(block.clone());
                     }
-                    if blockVecToSend.len() > 0 {
-                        peer.write(Message::Block(blockVecToSend.clone()));
-                        debug!("sending blocks")
+                }
+                drop(blockchain);
+                if blockVecToSend.len() > 0 {
+                    for block in blockVecToSend.iter() {
+                        peer.mark_known(&block.hash());
                     }
-
+                    send(&peer, Message::Block(blockVecToSend.clone()));
+                    debug!("sending blocks")
                 }
-                Message::Block(blockVec) => {
 
-                    //let mut newBlockParentMap = HashMap::new();
-                    let mut insertedBlocks: Vec<H256> = Vec::new();
-                    let mut getBlocks: Vec<H256> = Vec::new();
+            }
+            Message::Block(blockVec) => {
+                self.receive_blocks(&blockVec, &peer);
+            }
+            Message::NewTransactionHashes(transHashVec) => {
 
-                    for block in blockVec.iter() {
-                        orphanBlocks.insert((*block).header.parent,block.clone());
+                let mut wantedTrans: Vec<H256> = Vec::new();
+                let mempool = self.mempool.lock().unwrap();
+                for transHash in transHashVec.iter() {
+                    // a transaction the peer sent before is in the mempool or was rejected
+                    if peer.knows(transHash) {
+                        continue;
+                    }
+                    peer.mark_known(transHash);
+                    if !mempool.contains(transHash) {
+                        wantedTrans.push(*transHash);
                     }
+                }
+                for chunk in wantedTrans.chunks(MAX_TRANSACTIONS_PER_REQUEST) {
+                    send(&peer, Message::GetTransaction(chunk.to_vec()));
+                }
+            }
+            Message::GetTransaction(transHashVec) => {
+
+                let mempool = self.mempool.lock().unwrap();
+                let transVecToSend: Vec<SignedTransaction> = transHashVec.iter()
+                    .filter_map(|transHash| mempool.get(transHash).cloned())
+                    .collect();
+                if !transVecToSend.is_empty() {
+                    send(&peer, Message::Transaction(transVecToSend));
+                }
+            }
+            Message::Transaction(transVec) => {
 
-                    for block in blockVec.iter() {
-                        let blockHash: H256 = (*block).hash();
-                        if self.blockchain.lock().unwrap().blockMap.contains_key(&blockHash) {
+                let mut newTrans: Vec<H256> = Vec::new();
+                let blockchain = self.blockchain.lock().unwrap();
+                let context = ScriptContext::next_block(&blockchain);
+                let mut mempool = self.mempool.lock().unwrap();
+                for trans in transVec.iter() {
+                    let transHash = trans.hash();
+                    peer.mark_known(&transHash);
+                    if mempool.contains(&transHash) {
+                        continue;
+                    }
+                    if let Err(reason) = self.verifier.validate(trans, &context) {
+                        debug!("dropping transaction {}: {}", transHash, reason);
+                        continue;
+                    }
+                    // a transaction that cannot be in the next block waits with its sender
+                    let nextNonce = mempool.next_nonce(&trans.input, blockchain.next_nonce(&trans.input));
+                    if trans.nonce != nextNonce {
+                        debug!("dropping transaction {}: nonce {} where {} is next", transHash, trans.nonce, nextNonce);
+                        continue;
+                    }
+                    if mempool.insert(trans.clone()) {
+                        newTrans.push(transHash);
+                    }
+                }
+                drop(mempool);
+                drop(blockchain);
+                if !newTrans.is_empty() {
+                    self.server.announce_transactions(newTrans);
+                }
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS);
+                debug!("sending {} headers", headers.len());
+                send(&peer, Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                let blockchain = self.blockchain.lock().unwrap();
+                for header in headers.iter() {
+                    peer.mark_known(&header.hash());
+                }
+                match self.sync.accept_headers(&headers, &peer, &blockchain) {
+                    Ok(count) => {
+                        debug!("received {} new headers from {}", count, peer.addr());
+                        // a full batch means the peer has more headers for us
+                        if headers.len() == MAX_HEADERS {
+                            let mut locator = vec![headers[headers.len() - 1].hash()];
+                            locator.extend(blockchain.block_locator());
+                            send(&peer, Message::GetHeaders(locator));
+                        }
+                    }
+                    Err(e @ HeadersError::UnknownParent(_)) => {
+                        // start over from what we have
+                        debug!("headers from {} do not connect: {}", peer.addr(), e);
+                        send(&peer, Message::GetHeaders(blockchain.block_locator()));
+                    }
+                    Err(HeadersError::Invalid(e)) => {
+                        warn!("invalid headers from {}: {}", peer.addr(), e);
+                        peer.misbehaved(20, "invalid headers");
+                    }
+                }
+            }
+            Message::GetCompactBlock(blockHashVec) => {
+                if peer.is_congested() {
+                    debug!("Peer {} is congested, ignoring its block request", peer.addr());
+                    return;
+                }
+                let blockchain = self.blockchain.lock().unwrap();
+                let compactVec: Vec<CompactBlock> = blockHashVec.iter()
+                    .filter_map(|blockHash| blockchain.blockMap.get(blockHash))
+                    .map(CompactBlock::new)
+                    .collect();
+                drop(blockchain);
+                if !compactVec.is_empty() {
+                    for compact in compactVec.iter() {
+                        peer.mark_known(&compact.hash());
+                    }
+                    send(&peer, Message::CompactBlock(compactVec));
+                }
+            }
+            Message::CompactBlock(compactVec) => {
+                let mut blockVec: Vec<Block> = Vec::new();
+                let mut fullBlocks: Vec<H256> = Vec::new();
+                for compact in compactVec {
+                    let blockHash = compact.hash();
+                    peer.mark_known(&blockHash);
+                    if blockHash > compact.header.difficulty || self.blockchain.lock().unwrap().blockMap.contains_key(&blockHash) {
+                        continue;
+                    }
+                    let partial = PartialBlock::new(compact, &self.mempool.lock().unwrap());
+                    let partial = match partial {
+                        Ok(partial) => partial,
+                        Err(e) => {
+                            debug!("cannot use compact block {}: {}", blockHash, e);
+                            fullBlocks.push(blockHash);
                             continue;
-                        }else {
-                            if self.blockchain.lock().unwrap().blockMap.contains_key(&block.header.parent) && blockHash <= block.header.difficulty {
-                                self.blockchain.lock().unwrap().insert(&(block.clone()));
-                                insertedBlocks.push(blockHash);
-                                orphanBlocks.remove(&(*block).header.parent);
-                                let mut parent: H256 = blockHash;
-                                while orphanBlocks.contains_key(&parent) {
-                                    self.blockchain.lock().unwrap().insert(&(orphanBlocks[&parent].clone()));
-                                    let mut new_parent = orphanBlocks[&parent].hash();
-                                    orphanBlocks.remove(&parent);
-                                    parent = new_parent;
-                                    insertedBlocks.push(parent);
-                                }
-                                debug!("receiving blocks")
-                            }else {
-                                if blockHash <= block.header.difficulty {
-                                    //orphanBlocks.insert((*block).header.parent,block.clone());
-                                    getBlocks.push((*block).header.parent);
-                                }
+                        }
+                    };
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        match partial.into_block() {
+                            Ok(block) => blockVec.push(block),
+                            Err(e) => {
+                                debug!("cannot rebuild block {}: {}", blockHash, e);
+                                fullBlocks.push(blockHash);
                             }
                         }
+                        continue;
                     }
-                    if getBlocks.len() > 0 {
-                         peer.write(Message::GetBlock(getBlocks));
-                     } 
-                    if insertedBlocks.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(insertedBlocks)); 
+                    if !self.compact_blocks.lock().unwrap().insert(partial, peer.addr()) {
+                        fullBlocks.push(blockHash);
+                        continue;
                     }
-                         
+                    send(&peer, Message::GetBlockTransactions(blockHash, missing));
                 }
-                Message::NewTransactionHashes(transHashVec) => {
-
-                    let mut wantedTrans: Vec<H256> = Vec::new();
-                    
-                    
+                for chunk in fullBlocks.chunks(MAX_BLOCKS_PER_REQUEST) {
+                    send(&peer, Message::GetBlock(chunk.to_vec()));
+                }
+                if !blockVec.is_empty() {
+                    self.receive_blocks(&blockVec, &peer);
                 }
-                Message::GetTransaction(transHashVec) => {
+            }
+            Message::GetBlockTransactions(blockHash, indexes) => {
+                let blockchain = self.blockchain.lock().unwrap();
+                let block = match blockchain.blockMap.get(&blockHash) {
+                    Some(block) => block,
+                    None => return,
+                };
+                let transVec: Option<Vec<SignedTransaction>> = indexes.iter()
+                    .map(|i| block.content.data.get(*i as usize).cloned())
+                    .collect();
+                // a bad index means the peer cannot rebuild the block, so send all of it
+                let reply = match transVec {
+                    Some(transVec) => Message::BlockTransactions(blockHash, transVec),
+                    None => Message::Block(vec![block.clone()]),
+                };
+                drop(blockchain);
+                send(&peer, reply);
+            }
+            Message::BlockTransactions(blockHash, transVec) => {
+                let partial = self.compact_blocks.lock().unwrap().take(&blockHash, peer.addr());
+                let mut partial = match partial {
+                    Some(partial) => partial,
+                    None => {
+                        debug!("unexpected transactions for block {} from {}", blockHash, peer.addr());
+                        return;
+                    }
+                };
+                if let Err(e) = partial.fill(transVec) {
+                    debug!("wrong transactions for block {} from {}: {}", blockHash, peer.addr(), e);
+                    peer.misbehaved(10, "wrong block transactions");
+                    send(&peer, Message::GetBlock(vec![blockHash]));
+                    return;
+                }
+                // a mismatch may come from a mempool transaction colliding with a short ID
+                match partial.into_block() {
+                    Ok(block) => self.receive_blocks(&[block], &peer),
+                    Err(e) => {
+                        debug!("cannot rebuild block {}: {}", blockHash, e);
+                        send(&peer, Message::GetBlock(vec![blockHash]));
+                    }
+                }
+            }
+        }
+    }
 
-                    let mut transVecToSend: Vec<SignedTransaction> = Vec::new();
+    /// Check a block whose parent is in the blockchain, then the signatures of its transactions
+    fn check_block(&self, blockchain: &Blockchain, block: &Block) -> Result<(), String> {
+        blockchain.check_block(block)?;
+        let context = ScriptContext::after(blockchain, &block.header.parent);
+        self.verifier.validate_block(block, &context)
+    }
 
-                }
-                Message::Transaction(transVec) => {
+    /// Add blocks to the chain, parking the ones whose parent we do not have yet.
+    fn receive_blocks(&self, blockVec: &[Block], peer: &peer::Handle) {
+        let mut insertedBlocks: Vec<H256> = Vec::new();
+        let mut getBlocks: Vec<H256> = Vec::new();
 
-                    
-                         
+        for block in blockVec.iter() {
+            let blockHash: H256 = (*block).hash();
+            peer.mark_known(&blockHash);
+            if blockHash > block.header.difficulty {
+                peer.misbehaved(20, "block fails proof of work");
+                self.sync.block_rejected(&blockHash, peer.addr());
+                continue;
+            }
+            let mut blockchain = self.blockchain.lock().unwrap();
+            if blockchain.blockMap.contains_key(&blockHash) {
+                self.sync.block_received(&blockHash);
+                continue;
+            }
+            if !blockchain.blockMap.contains_key(&block.header.parent) {
+                // park it until the parent arrives, and ask for the parent unless it is on its way
+                let parent = block.header.parent;
+                let mut orphans = self.orphans.lock().unwrap();
+                let parked = orphans.insert(block.clone(), peer.addr());
+                if parked && !self.sync.is_scheduled(&parent) && orphans.request_parent(&parent) {
+                    getBlocks.push(parent);
+                }
+                if parked || orphans.contains(&blockHash) {
+                    self.sync.block_received(&blockHash);
+                } else {
+                    self.sync.block_rejected(&blockHash, peer.addr());
                 }
+                continue;
             }
-        println!("----------------         Chain length {:?}", self.blockchain.lock().unwrap().chainLength);// , self.blockchain.lock().unwrap().blockMap.keys().len());
-        println!("Chain Tip {:?}", self.blockchain.lock().unwrap().tip());
+            if let Err(reason) = self.check_block(&blockchain, block) {
+                peer.misbehaved(20, &reason);
+                self.sync.block_rejected(&blockHash, peer.addr());
+                continue;
+            }
+            blockchain.insert(block);
+            self.sync.block_received(&blockHash);
+            self.mempool.lock().unwrap().remove_confirmed(block, &blockchain);
+            insertedBlocks.push(blockHash);
+            // connect the orphans that were waiting for this block, recursively
+            let mut parents: Vec<H256> = vec![blockHash];
+            while let Some(parent) = parents.pop() {
+                let children = self.orphans.lock().unwrap().take_children(&parent);
+                for child in children.iter() {
+                    let childHash = child.hash();
+                    if blockchain.blockMap.contains_key(&childHash) {
+                        parents.push(childHash);
+                    } else if self.check_block(&blockchain, child).is_ok() {
+                        blockchain.insert(child);
+                        self.mempool.lock().unwrap().remove_confirmed(child, &blockchain);
+                        insertedBlocks.push(childHash);
+                        parents.push(childHash);
+                    }
+                }
+            }
+            debug!("receiving blocks")
+        }
+        for chunk in getBlocks.chunks(MAX_BLOCKS_PER_REQUEST) {
+            send(peer, Message::GetBlock(chunk.to_vec()));
+        }
+        if insertedBlocks.len() > 0 {
+            self.server.announce_blocks(insertedBlocks);
         }
+        self.sync.dispatch();
     }
 }
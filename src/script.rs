@@ -0,0 +1,541 @@
+//! A small stack-based script language for spending conditions.
+//!
+//! An address can be the hash of a locking script. Spending from it takes the locking script
+//! and an unlocking script, which may only push data. The unlocking script runs first, then the
+//! locking script on the stack it left, and the spend is valid if the top of the stack is true.
+//! There are no loops or jumps, only `If`/`Else`/`EndIf`, so a script runs at most once per op,
+//! and every run is bounded by a number of ops, a stack depth and a cost.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256};
+use ring::signature::{EdDSAParameters, VerificationAlgorithm};
+use serde::{Deserialize, Serialize};
+use untrusted::Input;
+
+use std::convert::TryInto;
+
+/// The largest number of ops in a script
+pub const MAX_OPS: usize = 200;
+/// The largest number of elements on the stack
+pub const MAX_STACK_DEPTH: usize = 100;
+/// The largest element pushed or computed
+pub const MAX_ELEMENT_SIZE: usize = 520;
+/// The largest total cost of running both scripts of a spend
+pub const MAX_COST: u32 = 1000;
+const SIGNATURE_COST: u32 = 100;
+const HASH_COST: u32 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Push(Vec<u8>),
+    /// Push a number, as 8 little-endian bytes
+    Number(u64),
+    Dup,
+    Drop,
+    Swap,
+    /// Replace the top element with its SHA256
+    Sha256,
+    /// Replace the top element with the last 20 bytes of its SHA256
+    Hash160,
+    Equal,
+    EqualVerify,
+    /// Fail unless the top element is true, removing it
+    Verify,
+    Not,
+    BoolAnd,
+    BoolOr,
+    /// Run the following ops up to `Else` or `EndIf` if the top element is true
+    If,
+    Else,
+    EndIf,
+    /// Pop a public key, then a signature of the transaction, and push whether it is valid
+    CheckSig,
+    CheckSigVerify,
+    /// Fail unless the transaction is in a block at least at the height on top of the stack
+    CheckHeightVerify,
+    /// Fail unless the transaction is in a block at least as late as the time in milliseconds on
+    /// top of the stack
+    CheckTimeVerify,
+    /// Fail
+    Return,
+}
+
+/// Why a script failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    TooManyOps,
+    StackOverflow,
+    StackUnderflow,
+    ElementTooLarge,
+    CostExceeded,
+    UnbalancedConditional,
+    /// The unlocking script does something other than push data
+    NotPushOnly,
+    InvalidNumber,
+    VerifyFailed,
+    /// A height or time lock is not over yet
+    Locked,
+    Return,
+    /// The scripts ran to the end without leaving true on the stack
+    False,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            ScriptError::TooManyOps => "too many ops",
+            ScriptError::StackOverflow => "stack overflow",
+            ScriptError::StackUnderflow => "stack underflow",
+            ScriptError::ElementTooLarge => "element too large",
+            ScriptError::CostExceeded => "cost limit exceeded",
+            ScriptError::UnbalancedConditional => "unbalanced conditional",
+            ScriptError::NotPushOnly => "unlocking script does more than push data",
+            ScriptError::InvalidNumber => "invalid number",
+            ScriptError::VerifyFailed => "verify failed",
+            ScriptError::Locked => "lock time not reached",
+            ScriptError::Return => "script returned",
+            ScriptError::False => "script evaluated to false",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script(pub Vec<Op>);
+
+impl Script {
+    /// The address of funds locked by this script
+    pub fn address(&self) -> H160 {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(b"script");
+        ctx.update(&bincode::serialize(self).unwrap());
+        let hash: [u8; 20] = ctx.finish().as_ref()[12..].try_into().unwrap();
+        hash.into()
+    }
+
+    fn is_push_only(&self) -> bool {
+        self.0
+            .iter()
+            .all(|op| matches!(op, Op::Push(_) | Op::Number(_)))
+    }
+}
+
+/// The scripts spending from a script address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScriptSpend {
+    pub locking: Script,
+    pub unlocking: Script,
+}
+
+/// Where a transaction is being included, for height and time locks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    /// The height of the block including the transaction
+    pub height: u64,
+    /// The median time past of the parent of the block including the transaction, in
+    /// milliseconds since the Unix epoch
+    pub time: u64,
+}
+
+impl Context {
+    /// The context of a transaction included in the next block of the longest chain
+    pub fn next_block(blockchain: &Blockchain) -> Self {
+        Self::after(blockchain, &blockchain.tip())
+    }
+
+    /// The context of a transaction included in a child of the block `parent`
+    pub fn after(blockchain: &Blockchain, parent: &H256) -> Self {
+        Context {
+            height: blockchain.height(parent).map_or(0, |x| x as u64 + 1),
+            time: blockchain.median_time_past(parent) as u64,
+        }
+    }
+}
+
+fn is_true(element: &[u8]) -> bool {
+    element.iter().any(|x| *x != 0)
+}
+
+fn number(element: &[u8]) -> Result<u64, ScriptError> {
+    if element.len() > 8 {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..element.len()].copy_from_slice(element);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+fn sha256(element: &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, element)
+        .as_ref()
+        .to_vec()
+}
+
+/// A script run
+struct Machine<'a> {
+    stack: Vec<Vec<u8>>,
+    cost: u32,
+    message: &'a [u8],
+    context: &'a Context,
+}
+
+impl<'a> Machine<'a> {
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn push(&mut self, element: Vec<u8>) -> Result<(), ScriptError> {
+        if element.len() > MAX_ELEMENT_SIZE {
+            return Err(ScriptError::ElementTooLarge);
+        }
+        if self.stack.len() >= MAX_STACK_DEPTH {
+            return Err(ScriptError::StackOverflow);
+        }
+        self.stack.push(element);
+        Ok(())
+    }
+
+    fn charge(&mut self, cost: u32) -> Result<(), ScriptError> {
+        self.cost += cost;
+        if self.cost > MAX_COST {
+            return Err(ScriptError::CostExceeded);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, script: &Script) -> Result<(), ScriptError> {
+        if script.0.len() > MAX_OPS {
+            return Err(ScriptError::TooManyOps);
+        }
+        // for each enclosing `If`, whether its current branch runs
+        let mut branches: Vec<bool> = Vec::new();
+        for op in script.0.iter() {
+            self.charge(1)?;
+            let running = branches.iter().all(|x| *x);
+            match op {
+                Op::If => {
+                    let taken = running && is_true(&self.pop()?);
+                    branches.push(taken);
+                    continue;
+                }
+                Op::Else => {
+                    let taken = branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                    let running_outer = branches.iter().all(|x| *x);
+                    branches.push(running_outer && !taken);
+                    continue;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                    continue;
+                }
+                _ if !running => continue,
+                _ => {}
+            }
+            self.step(op)?;
+        }
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<(), ScriptError> {
+        match op {
+            Op::Push(element) => self.push(element.clone())?,
+            Op::Number(n) => self.push(n.to_le_bytes().to_vec())?,
+            Op::Dup => {
+                let top = self
+                    .stack
+                    .last()
+                    .ok_or(ScriptError::StackUnderflow)?
+                    .clone();
+                self.push(top)?;
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a)?;
+                self.push(b)?;
+            }
+            Op::Sha256 => {
+                self.charge(HASH_COST)?;
+                let element = self.pop()?;
+                self.push(sha256(&element))?;
+            }
+            Op::Hash160 => {
+                self.charge(HASH_COST)?;
+                let element = self.pop()?;
+                self.push(sha256(&element)[12..].to_vec())?;
+            }
+            Op::Equal | Op::EqualVerify => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(boolean(a == b))?;
+                if *op == Op::EqualVerify {
+                    self.step(&Op::Verify)?;
+                }
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::Not => {
+                let value = is_true(&self.pop()?);
+                self.push(boolean(!value))?;
+            }
+            Op::BoolAnd | Op::BoolOr => {
+                let a = is_true(&self.pop()?);
+                let b = is_true(&self.pop()?);
+                let value = if *op == Op::BoolAnd { a && b } else { a || b };
+                self.push(boolean(value))?;
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                self.charge(SIGNATURE_COST)?;
+                let pub_key = self.pop()?;
+                let signature = self.pop()?;
+                let valid = VerificationAlgorithm::verify(
+                    &EdDSAParameters,
+                    Input::from(&pub_key),
+                    Input::from(self.message),
+                    Input::from(&signature),
+                )
+                .is_ok();
+                self.push(boolean(valid))?;
+                if *op == Op::CheckSigVerify {
+                    self.step(&Op::Verify)?;
+                }
+            }
+            Op::CheckHeightVerify => {
+                if self.context.height < number(&self.pop()?)? {
+                    return Err(ScriptError::Locked);
+                }
+            }
+            Op::CheckTimeVerify => {
+                if self.context.time < number(&self.pop()?)? {
+                    return Err(ScriptError::Locked);
+                }
+            }
+            Op::Return => return Err(ScriptError::Return),
+            Op::If | Op::Else | Op::EndIf => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+/// Run an unlocking script then a locking script, with `message` the signed bytes of the
+/// transaction.
+pub fn execute(
+    unlocking: &Script,
+    locking: &Script,
+    message: &[u8],
+    context: &Context,
+) -> Result<(), ScriptError> {
+    if !unlocking.is_push_only() {
+        return Err(ScriptError::NotPushOnly);
+    }
+    let mut machine = Machine {
+        stack: Vec::new(),
+        cost: 0,
+        message,
+        context,
+    };
+    machine.run(unlocking)?;
+    machine.run(locking)?;
+    match machine.stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::False),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use ring::signature::KeyPair;
+
+    const CONTEXT: Context = Context {
+        height: 100,
+        time: 1_000_000,
+    };
+
+    fn run(unlocking: Vec<Op>, locking: Vec<Op>) -> Result<(), ScriptError> {
+        execute(&Script(unlocking), &Script(locking), b"message", &CONTEXT)
+    }
+
+    #[test]
+    fn signatures() {
+        let key = key_pair::random();
+        let pub_key = key.public_key().as_ref().to_vec();
+        let signature = key.sign(b"message").as_ref().to_vec();
+        let locking = vec![Op::Push(pub_key.clone()), Op::CheckSig];
+        assert_eq!(
+            run(vec![Op::Push(signature.clone())], locking.clone()),
+            Ok(())
+        );
+        let other = key_pair::random().sign(b"message").as_ref().to_vec();
+        assert_eq!(run(vec![Op::Push(other)], locking), Err(ScriptError::False));
+
+        // pay to public key hash
+        let locking = vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(sha256(&pub_key)[12..].to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ];
+        let unlocking = vec![Op::Push(signature), Op::Push(pub_key)];
+        assert_eq!(run(unlocking, locking.clone()), Ok(()));
+        let wrong_key = key_pair::random().public_key().as_ref().to_vec();
+        assert_eq!(
+            run(vec![Op::Push(vec![0; 64]), Op::Push(wrong_key)], locking),
+            Err(ScriptError::VerifyFailed)
+        );
+    }
+
+    #[test]
+    fn hash_and_time_locks() {
+        let hash_lock = vec![Op::Sha256, Op::Push(sha256(b"secret")), Op::Equal];
+        assert_eq!(
+            run(vec![Op::Push(b"secret".to_vec())], hash_lock.clone()),
+            Ok(())
+        );
+        assert_eq!(
+            run(vec![Op::Push(b"guess".to_vec())], hash_lock),
+            Err(ScriptError::False)
+        );
+
+        let height_lock = |height| vec![Op::Number(height), Op::CheckHeightVerify, Op::Number(1)];
+        assert_eq!(run(vec![], height_lock(100)), Ok(()));
+        assert_eq!(run(vec![], height_lock(101)), Err(ScriptError::Locked));
+        let time_lock = |time| vec![Op::Number(time), Op::CheckTimeVerify, Op::Number(1)];
+        assert_eq!(run(vec![], time_lock(999_999)), Ok(()));
+        assert_eq!(run(vec![], time_lock(1_000_001)), Err(ScriptError::Locked));
+        assert_eq!(
+            run(vec![], vec![Op::Push(vec![1; 9]), Op::CheckHeightVerify]),
+            Err(ScriptError::InvalidNumber)
+        );
+
+        // the preimage before height 200, anything after
+        let either = vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(sha256(b"secret")),
+            Op::Equal,
+            Op::Else,
+            Op::Number(200),
+            Op::CheckHeightVerify,
+            Op::Number(1),
+            Op::EndIf,
+        ];
+        let claim = vec![Op::Push(b"secret".to_vec()), Op::Number(1)];
+        assert_eq!(run(claim, either.clone()), Ok(()));
+        assert_eq!(run(vec![Op::Number(0)], either), Err(ScriptError::Locked));
+    }
+
+    #[test]
+    fn logic_and_stack() {
+        let t = || Op::Number(1);
+        let f = || Op::Push(vec![]);
+        assert_eq!(run(vec![], vec![t(), f(), Op::BoolOr]), Ok(()));
+        assert_eq!(
+            run(vec![], vec![t(), f(), Op::BoolAnd]),
+            Err(ScriptError::False)
+        );
+        assert_eq!(run(vec![], vec![f(), Op::Not]), Ok(()));
+        assert_eq!(run(vec![], vec![Op::Number(0), Op::Not]), Ok(()));
+        assert_eq!(
+            run(vec![], vec![t(), f(), Op::Swap, Op::Drop]),
+            Err(ScriptError::False)
+        );
+        assert_eq!(run(vec![], vec![]), Err(ScriptError::False));
+        assert_eq!(
+            run(vec![], vec![Op::Drop]),
+            Err(ScriptError::StackUnderflow)
+        );
+        assert_eq!(run(vec![], vec![t(), Op::Return]), Err(ScriptError::Return));
+        // the ops of a branch not taken do not run
+        assert_eq!(
+            run(
+                vec![],
+                vec![f(), Op::If, Op::Return, Op::Else, t(), Op::EndIf]
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            run(
+                vec![],
+                vec![
+                    f(),
+                    Op::If,
+                    t(),
+                    Op::If,
+                    Op::Return,
+                    Op::EndIf,
+                    Op::EndIf,
+                    t()
+                ]
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            run(vec![], vec![t(), Op::If, t()]),
+            Err(ScriptError::UnbalancedConditional)
+        );
+        assert_eq!(
+            run(vec![], vec![Op::EndIf]),
+            Err(ScriptError::UnbalancedConditional)
+        );
+        assert_eq!(
+            run(vec![], vec![Op::Else]),
+            Err(ScriptError::UnbalancedConditional)
+        );
+        assert_eq!(run(vec![t()], vec![Op::Not, Op::Not]), Ok(()));
+        assert_eq!(
+            run(vec![t(), Op::Dup], vec![]),
+            Err(ScriptError::NotPushOnly)
+        );
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(
+            run(vec![], vec![Op::Number(1); MAX_OPS + 1]),
+            Err(ScriptError::TooManyOps)
+        );
+        assert_eq!(
+            run(vec![], vec![Op::Number(1); MAX_STACK_DEPTH + 1]),
+            Err(ScriptError::StackOverflow)
+        );
+        assert_eq!(
+            run(vec![Op::Push(vec![1; MAX_ELEMENT_SIZE + 1])], vec![]),
+            Err(ScriptError::ElementTooLarge)
+        );
+        // signature checks are expensive
+        let mut checks = Vec::new();
+        for _ in 0..10 {
+            checks.extend(vec![
+                Op::Push(vec![0; 64]),
+                Op::Push(vec![0; 32]),
+                Op::CheckSig,
+                Op::Drop,
+            ]);
+        }
+        assert_eq!(run(vec![], checks), Err(ScriptError::CostExceeded));
+        // and so are hashes
+        let mut hashes = vec![Op::Number(1)];
+        hashes.extend(vec![Op::Sha256; MAX_OPS - 1]);
+        assert_eq!(run(vec![], hashes), Err(ScriptError::CostExceeded));
+    }
+}
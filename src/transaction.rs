@@ -6,6 +6,9 @@ use crate::crypto::hash::{H256, H160, Hashable};
 //use crate::crypto::address::{H160};
 use std::convert::TryInto;
 use crate::crypto::key_pair;
+use crate::multisig::Multisig;
+use crate::script::{self, Context, ScriptSpend};
+use crate::blockchain::chain_id;
 
 
 #[derive(Serialize, Deserialize, Debug, Default,Clone)]
@@ -13,8 +16,15 @@ pub struct Transaction {
     pub input: H160,
     pub output: H160,
     pub amount: f32,
+    /// The block height or time before which the transaction cannot be included, 0 for none
+    pub lock_time: u64,
+    /// The number of transactions the input address sent before this one
+    pub nonce: u64,
 }
 
+/// Lock times below this are block heights, the others are times in milliseconds
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+
 impl Hashable for Transaction {
     fn hash(&self) -> H256 {
          let byte_transaction = bincode::serialize(&self).unwrap();
@@ -27,8 +37,14 @@ pub struct SignedTransaction {
     pub input: H160,
     pub output: H160,
     pub amount: f32,
+    pub lock_time: u64,
+    pub nonce: u64,
     pub pub_key: H256,
     pub signature: [H256;2],
+    /// Set when spending from a multisig address, leaving `pub_key` and `signature` zero
+    pub multisig: Option<Multisig>,
+    /// Set when spending from a script address, leaving the other signature fields empty
+    pub script: Option<ScriptSpend>,
 }
 
 impl Hashable for SignedTransaction {
@@ -38,6 +54,87 @@ impl Hashable for SignedTransaction {
     }
 }
 
+impl SignedTransaction {
+    /// The transaction that was signed
+    pub fn raw(&self) -> Transaction {
+        Transaction{input: self.input, output: self.output, amount: self.amount, lock_time: self.lock_time, nonce: self.nonce}
+    }
+
+    /// A transaction spending from a multisig address with the signatures gathered
+    pub fn from_multisig(raw: &Transaction, multisig: Multisig) -> Self {
+        SignedTransaction{input: raw.input, output: raw.output, amount: raw.amount, lock_time: raw.lock_time, nonce: raw.nonce, pub_key: H256::default(), signature: [H256::default(); 2], multisig: Some(multisig), script: None}
+    }
+
+    /// A transaction spending from a script address
+    pub fn from_script(raw: &Transaction, spend: ScriptSpend) -> Self {
+        SignedTransaction{input: raw.input, output: raw.output, amount: raw.amount, lock_time: raw.lock_time, nonce: raw.nonce, pub_key: H256::default(), signature: [H256::default(); 2], multisig: None, script: Some(spend)}
+    }
+
+    /// Whether the lock time allows including the transaction in a block in `context`
+    pub fn is_final(&self, context: &Context) -> bool {
+        if self.lock_time == 0 {
+            true
+        } else if self.lock_time < LOCK_TIME_THRESHOLD {
+            context.height >= self.lock_time
+        } else {
+            context.time >= self.lock_time
+        }
+    }
+
+    /// Check that the amount is valid, that the transaction can be included in a block in
+    /// `context`, and that it is signed by the owner of its input address or satisfies its script
+    pub fn validate(&self, context: &Context) -> Result<(), String> {
+        self.check_terms(context)?;
+        self.verify_signature(context)
+    }
+
+    /// Check that the amount is valid and that the transaction can be included in a block in
+    /// `context`
+    pub fn check_terms(&self, context: &Context) -> Result<(), String> {
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            return Err(format!("invalid amount {}", self.amount));
+        }
+        if !self.is_final(context) {
+            return Err(format!("locked until {}", self.lock_time));
+        }
+        Ok(())
+    }
+
+    /// Check that the transaction is signed by the owner of its input address, or satisfies its
+    /// script in `context`. Only a script makes the result depend on `context`.
+    pub fn verify_signature(&self, context: &Context) -> Result<(), String> {
+        let single_key = self.pub_key != H256::default() || self.signature != [H256::default(); 2];
+        if let Some(spend) = &self.script {
+            if single_key || self.multisig.is_some() {
+                return Err("a script spend has no other signature".to_string());
+            }
+            if spend.locking.address() != self.input {
+                return Err("input address does not belong to the locking script".to_string());
+            }
+            let trans = signing_payload(&self.raw(), &chain_id());
+            return script::execute(&spend.unlocking, &spend.locking, &trans, context).map_err(|e| e.to_string());
+        }
+        if let Some(multisig) = &self.multisig {
+            if single_key {
+                return Err("a multisig spend has no single-key signature".to_string());
+            }
+            if multisig.address() != self.input {
+                return Err("input address does not belong to the multisig keys".to_string());
+            }
+            return multisig.verify(&signing_payload(&self.raw(), &chain_id()));
+        }
+        let pub_key: [u8;32] = self.pub_key.into();
+        let truncated: [u8;20] = pub_key[12..].try_into().unwrap();
+        if H160::from(truncated) != self.input {
+            return Err("input address does not belong to the public key".to_string());
+        }
+        let trans = signing_payload(&self.raw(), &chain_id());
+        let sig = convertH256ToSigRef(self.signature);
+        VerificationAlgorithm::verify(&EdDSAParameters, Input::from(&pub_key), Input::from(&trans), Input::from(&sig))
+            .map_err(|_| "invalid signature".to_string())
+    }
+}
+
 pub fn generate_random_signed_transaction() -> (Transaction,SignedTransaction,Ed25519KeyPair) {
     let mut rng = rand::thread_rng();
     let key1 = key_pair::random();
@@ -45,11 +142,11 @@ pub fn generate_random_signed_transaction() -> (Transaction,SignedTransaction,Ed
     let addr1 = convertPubKeyToH160(&(key1.public_key()));
     let addr2 = convertPubKeyToH160(&(key2.public_key()));
     
-    let trans = Transaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0)};
+    let trans = Transaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0), lock_time: 0, nonce: 0};
     let sig = sign(&trans,&key1);
     let sigH256: [H256;2] = convertSigToH256(&sig);
     let pub_key = convertPubKeyToH256(&(key1.public_key()));
-    let signed_trans = SignedTransaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0),pub_key: pub_key,signature:sigH256};
+    let signed_trans = SignedTransaction{input: addr1, output: addr2, amount: trans.amount, lock_time: 0, nonce: 0,pub_key: pub_key,signature:sigH256, multisig: None, script: None};
 
     (trans,signed_trans,key1)
 }
@@ -83,16 +180,25 @@ pub fn convertH256ToSigRef(signature: [H256;2]) -> Vec<u8> {
     [refer1,refer2].concat()
 }
 
+/// The bytes signed for a transaction on the chain `chain_id`. Binding the chain keeps a
+/// transaction signed for one network from being replayed on another.
+pub fn signing_payload(t: &Transaction, chain_id: &H256) -> Vec<u8> {
+    [chain_id.as_ref(), &bincode::serialize(&t).unwrap()].concat()
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let byte_transaction = bincode::serialize(&t).unwrap();
-    let sig = Ed25519KeyPair::sign(&key, &byte_transaction);
-    sig
+    sign_for_chain(t, &chain_id(), key)
+}
+
+/// Create digital signature of a transaction on another chain
+pub fn sign_for_chain(t: &Transaction, chain_id: &H256, key: &Ed25519KeyPair) -> Signature {
+    Ed25519KeyPair::sign(key, &signing_payload(t, chain_id))
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let trans: &[u8] = &(&(bincode::serialize(&t).unwrap()));
+    let trans: &[u8] = &signing_payload(t, &chain_id());
     let msg = Input::from(trans);
     let sig = Input::from(signature.as_ref());
     let pub_key = Input::from(public_key.as_ref());
@@ -112,11 +218,11 @@ mod tests {
         let addr1 = convertPubKeyToH160(&(key1.public_key()));
         let addr2 = convertPubKeyToH160(&(key2.public_key()));
         
-        let trans = Transaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0)};
+        let trans = Transaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0), lock_time: 0, nonce: 0};
         let sig = sign(&trans,&key1);
         let sigH256: [H256;2] = convertSigToH256(&sig);
         let pub_key = convertPubKeyToH256(&(key1.public_key()));
-        let signed_trans = SignedTransaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0),pub_key: pub_key,signature:sigH256};
+        let signed_trans = SignedTransaction{input: addr1, output: addr2, amount: rng.gen_range(0.0,10.0), lock_time: 0, nonce: 0,pub_key: pub_key,signature:sigH256, multisig: None, script: None};
 
         (trans,signed_trans,key1)
     }
@@ -128,4 +234,70 @@ mod tests {
         let signature = sign(&t, &key);
         assert!(verify(&t, &(key.public_key()), &signature));
     }
+
+    use crate::script::{Op, Script};
+
+    const CONTEXT: Context = Context{height: 1, time: 0};
+
+    #[test]
+    fn validate_signed() {
+        let (t,_,key) = generate_random_transaction();
+        let sigH256 = convertSigToH256(&sign(&t, &key));
+        let pub_key = convertPubKeyToH256(key.public_key());
+        let mut signed_trans = SignedTransaction{input: t.input, output: t.output, amount: t.amount, lock_time: t.lock_time, nonce: t.nonce, pub_key, signature: sigH256, multisig: None, script: None};
+        assert!(signed_trans.validate(&CONTEXT).is_ok());
+        signed_trans.amount += 1.0;
+        assert!(signed_trans.validate(&CONTEXT).is_err());
+        signed_trans.amount = t.amount;
+        signed_trans.input = signed_trans.output;
+        assert!(signed_trans.validate(&CONTEXT).is_err());
+
+        // spending from a script locked after height 1
+        let locking = Script(vec![Op::Number(2), Op::CheckHeightVerify, Op::Number(1)]);
+        let raw = Transaction{input: locking.address(), output: t.output, amount: 1.0, lock_time: 0, nonce: 0};
+        let spend = ScriptSpend{locking, unlocking: Script(vec![])};
+        let mut scripted = SignedTransaction::from_script(&raw, spend);
+        assert!(scripted.validate(&CONTEXT).is_err());
+        assert!(scripted.validate(&Context{height: 2, time: 0}).is_ok());
+        scripted.input = t.input;
+        assert!(scripted.validate(&Context{height: 2, time: 0}).is_err());
+    }
+
+    #[test]
+    fn lock_time() {
+        let mut t = SignedTransaction{amount: 1.0, ..Default::default()};
+        let early = Context{height: 9, time: LOCK_TIME_THRESHOLD + 99};
+        let late = Context{height: 10, time: LOCK_TIME_THRESHOLD + 100};
+        assert!(t.is_final(&early));
+        t.lock_time = 10;
+        assert!(!t.is_final(&early));
+        assert!(t.is_final(&late));
+        t.lock_time = LOCK_TIME_THRESHOLD + 100;
+        assert!(!t.is_final(&early));
+        assert!(t.is_final(&late));
+        // the lock time is signed
+        let (raw, _, key) = generate_random_transaction();
+        let raw = Transaction{lock_time: 10, ..raw};
+        let mut signed = SignedTransaction{input: raw.input, output: raw.output, amount: raw.amount, lock_time: raw.lock_time, nonce: raw.nonce, pub_key: convertPubKeyToH256(key.public_key()), signature: convertSigToH256(&sign(&raw, &key)), multisig: None, script: None};
+        assert_eq!(signed.validate(&early), Err("locked until 10".to_string()));
+        assert!(signed.validate(&late).is_ok());
+        signed.lock_time = 9;
+        assert!(signed.validate(&late).is_err());
+    }
+
+    #[test]
+    fn replay_protection() {
+        let (raw, _, key) = generate_random_transaction();
+        let raw = Transaction{amount: 1.0, ..raw};
+        let pub_key = convertPubKeyToH256(key.public_key());
+        let mut signed = SignedTransaction{input: raw.input, output: raw.output, amount: raw.amount, lock_time: raw.lock_time, nonce: raw.nonce, pub_key, signature: convertSigToH256(&sign(&raw, &key)), multisig: None, script: None};
+        assert!(signed.validate(&CONTEXT).is_ok());
+        // signed for the test network
+        let other_chain = crate::blockchain::Blockchain::for_network(crate::crypto::address::Network::Test).chain_id();
+        let other = convertSigToH256(&sign_for_chain(&raw, &other_chain, &key));
+        assert!(SignedTransaction{signature: other, ..signed.clone()}.validate(&CONTEXT).is_err());
+        // the nonce is signed
+        signed.nonce = 1;
+        assert!(signed.validate(&CONTEXT).is_err());
+    }
 }
@@ -0,0 +1,160 @@
+//! Signature verification shared by the worker threads.
+//!
+//! A transaction is verified when it enters the mempool and again when a block including it
+//! arrives. The verifier remembers the hashes of transactions whose signatures were valid, so the
+//! second check is a cache lookup, and verifies the rest of a block in parallel. A script spend
+//! is never cached, as its result depends on the height and time of the block.
+
+use crate::block::Block;
+use crate::crypto::hash::{Hashable, H256};
+use crate::script::Context;
+use crate::transaction::SignedTransaction;
+use lru::LruCache;
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::thread;
+
+/// The number of verified transactions remembered by default
+pub const CACHE_SIZE: usize = 100_000;
+/// The smallest number of signatures worth a thread of their own
+const MIN_BATCH: usize = 16;
+
+pub struct Verifier {
+    /// The hashes of transactions with valid signatures, least recently used first
+    cache: Mutex<LruCache<H256, ()>>,
+    threads: usize,
+}
+
+impl Verifier {
+    /// A verifier remembering `cache_size` transactions and verifying blocks on up to `threads`
+    /// threads
+    pub fn new(cache_size: usize, threads: usize) -> Self {
+        Verifier {
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(cache_size.max(1)).unwrap())),
+            threads: threads.max(1),
+        }
+    }
+
+    fn cacheable(transaction: &SignedTransaction) -> bool {
+        transaction.script.is_none()
+    }
+
+    /// Validate a transaction for a block in `context`, skipping the signature check if it
+    /// passed already.
+    pub fn validate(
+        &self,
+        transaction: &SignedTransaction,
+        context: &Context,
+    ) -> Result<(), String> {
+        transaction.check_terms(context)?;
+        let hash = transaction.hash();
+        if self.cache.lock().unwrap().get(&hash).is_some() {
+            return Ok(());
+        }
+        transaction.verify_signature(context)?;
+        if Self::cacheable(transaction) {
+            self.cache.lock().unwrap().put(hash, ());
+        }
+        Ok(())
+    }
+
+    /// Validate every transaction of a block whose parent gives `context`, verifying the
+    /// signatures not in the cache in parallel. Returns the first error found.
+    pub fn validate_block(&self, block: &Block, context: &Context) -> Result<(), String> {
+        let transactions = &block.content.data;
+        for transaction in transactions.iter() {
+            transaction
+                .check_terms(context)
+                .map_err(|e| format!("transaction {}: {}", transaction.hash(), e))?;
+        }
+        let unverified: Vec<(H256, &SignedTransaction)> = {
+            let mut cache = self.cache.lock().unwrap();
+            transactions
+                .iter()
+                .map(|x| (x.hash(), x))
+                .filter(|(hash, _)| cache.get(hash).is_none())
+                .collect()
+        };
+        if unverified.is_empty() {
+            return Ok(());
+        }
+        let batch = unverified.len().div_ceil(self.threads).max(MIN_BATCH);
+        let verify = |batch: &[(H256, &SignedTransaction)]| -> Result<(), String> {
+            for (hash, transaction) in batch.iter() {
+                transaction
+                    .verify_signature(context)
+                    .map_err(|e| format!("transaction {}: {}", hash, e))?;
+            }
+            Ok(())
+        };
+        let mut batches = unverified.chunks(batch);
+        let first = batches.next().unwrap();
+        thread::scope(|scope| {
+            let handles: Vec<_> = batches
+                .map(|batch| scope.spawn(move || verify(batch)))
+                .collect();
+            // the first batch runs on this thread
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .fold(verify(first), |result, x| result.and(x))
+        })?;
+        let mut cache = self.cache.lock().unwrap();
+        for (hash, transaction) in unverified.iter() {
+            if Self::cacheable(transaction) {
+                cache.put(*hash, ());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |x| x.get());
+        Verifier::new(CACHE_SIZE, threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::H160;
+    use crate::transaction::generate_random_signed_transaction;
+
+    const CONTEXT: Context = Context { height: 1, time: 0 };
+
+    #[test]
+    fn blocks_and_cache() {
+        let verifier = Verifier::new(1000, 4);
+        let mut block = generate_random_block(&H256::from([0; 32]));
+        block.content.data = (0..100)
+            .map(|_| generate_random_signed_transaction().1)
+            .collect();
+        let relayed = block.content.data[7].clone();
+        assert!(verifier.validate(&relayed, &CONTEXT).is_ok());
+        assert!(verifier.cache.lock().unwrap().contains(&relayed.hash()));
+        assert!(verifier.validate_block(&block, &CONTEXT).is_ok());
+        assert_eq!(verifier.cache.lock().unwrap().len(), 100);
+
+        // a bad signature in the last batch
+        block.content.data[99].output = H160::from([1; 20]);
+        let bad = block.content.data[99].hash();
+        assert!(verifier
+            .validate_block(&block, &CONTEXT)
+            .unwrap_err()
+            .contains(&bad.to_string()));
+        assert!(!verifier.cache.lock().unwrap().contains(&bad));
+
+        // the least recently used are forgotten
+        block.content.data.truncate(99);
+        let small = Verifier::new(10, 1);
+        assert!(small.validate_block(&block, &CONTEXT).is_ok());
+        let cache = small.cache.lock().unwrap();
+        assert_eq!(cache.len(), 10);
+        assert!(cache.contains(&block.content.data[98].hash()));
+        assert!(!cache.contains(&block.content.data[0].hash()));
+    }
+}
@@ -0,0 +1,490 @@
+//! A wallet holding Ed25519 keys in passphrase-encrypted files.
+//!
+//! Keys are derived from the mnemonic seed phrase kept in the `seed` file, so the phrase alone
+//! restores every address. Each key is also stored in its own `<address>.key` file in the wallet
+//! directory, as a PKCS#8 document. All files are encrypted with ChaCha20-Poly1305 under a key
+//! derived from the passphrase with PBKDF2-HMAC-SHA256.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256};
+use crate::crypto::hd::{self, ExtendedKey};
+use crate::htlc::{self, Htlc};
+use crate::mempool::Mempool;
+use crate::script::Context;
+use crate::transaction::{self, SignedTransaction, Transaction};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+/// The first bytes of a key file, naming its format
+const MAGIC: &[u8] = b"WKEY1";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// The file holding the encrypted mnemonic
+const SEED_FILE: &str = "seed";
+/// The derivation path of the wallet keys, followed by the index of each key
+const ACCOUNT_PATH: [u32; 4] = [44, 0, 0, 0];
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+/// Encrypt a PKCS#8 document or a mnemonic into the contents of a wallet file.
+fn encrypt(secret: &[u8], passphrase: &str) -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).unwrap();
+    rng.fill(&mut nonce).unwrap();
+    let mut sealed = secret.to_vec();
+    derive_key(passphrase, &salt)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(MAGIC),
+            &mut sealed,
+        )
+        .unwrap();
+    [MAGIC, &salt[..], &nonce[..], &sealed].concat()
+}
+
+/// Decrypt the contents of a wallet file.
+fn decrypt(file: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    if file.len() < MAGIC.len() + SALT_LEN + NONCE_LEN || !file.starts_with(MAGIC) {
+        return Err("not a wallet file".to_string());
+    }
+    let (salt, rest) = file[MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::assume_unique_for_key(nonce.try_into().unwrap());
+    let mut sealed = sealed.to_vec();
+    let secret = derive_key(passphrase, salt)
+        .open_in_place(nonce, Aad::from(MAGIC), &mut sealed)
+        .map_err(|_| "wrong passphrase or corrupted wallet file".to_string())?;
+    Ok(secret.to_vec())
+}
+
+/// The transactions of the longest chain
+fn confirmed_transactions(blockchain: &Blockchain) -> impl Iterator<Item = &SignedTransaction> {
+    blockchain
+        .all_blocks_in_longest_chain()
+        .into_iter()
+        .flat_map(move |hash| blockchain.blockMap[&hash].content.data.iter())
+}
+
+/// The balance of the wallet addresses
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Balance {
+    /// Received minus sent in the longest chain
+    pub confirmed: f32,
+    /// The change made by transactions still in the mempool
+    pub pending: f32,
+}
+
+/// The change of the balance of the addresses matching `is_ours` made by some transactions
+fn balance_change<'a>(
+    is_ours: impl Fn(&H160) -> bool,
+    transactions: impl Iterator<Item = &'a SignedTransaction>,
+) -> HashMap<H160, f32> {
+    let mut change: HashMap<H160, f32> = HashMap::new();
+    for transaction in transactions {
+        if is_ours(&transaction.input) {
+            *change.entry(transaction.input).or_default() -= transaction.amount;
+        }
+        if is_ours(&transaction.output) {
+            *change.entry(transaction.output).or_default() += transaction.amount;
+        }
+    }
+    change
+}
+
+pub struct Wallet {
+    dir: PathBuf,
+    passphrase: String,
+    mnemonic: String,
+    seed: [u8; 64],
+    /// The index of the next key to derive
+    next_index: u32,
+    keys: HashMap<H160, Ed25519KeyPair>,
+    /// The addresses in the order they were loaded or created
+    addresses: Vec<H160>,
+}
+
+impl Wallet {
+    /// Open the wallet in a directory, creating the directory and a new seed if needed. Fails if
+    /// a wallet file cannot be decrypted with the passphrase.
+    pub fn open(dir: &Path, passphrase: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let seed_path = dir.join(SEED_FILE);
+        let mnemonic = if seed_path.exists() {
+            let phrase = decrypt(&std::fs::read(&seed_path)?, passphrase)
+                .map_err(|e| invalid_data(format!("{}: {}", seed_path.display(), e)))?;
+            String::from_utf8(phrase).map_err(|e| invalid_data(e.to_string()))?
+        } else {
+            let mnemonic = hd::generate_mnemonic();
+            std::fs::write(&seed_path, encrypt(mnemonic.as_bytes(), passphrase))?;
+            mnemonic
+        };
+        let seed = hd::mnemonic_to_seed(&mnemonic, "").map_err(invalid_data)?;
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|x| x.path()))
+            .filter(|path| path.extension().is_some_and(|x| x == "key"))
+            .collect();
+        paths.sort();
+        let mut wallet = Wallet {
+            dir: dir.to_path_buf(),
+            passphrase: passphrase.to_string(),
+            mnemonic,
+            seed,
+            next_index: 0,
+            keys: HashMap::new(),
+            addresses: Vec::new(),
+        };
+        for path in paths {
+            let pkcs8 = decrypt(&std::fs::read(&path)?, passphrase)
+                .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+            let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+                .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+            wallet.add(key);
+        }
+        // skip the keys derived already
+        while wallet
+            .keys
+            .contains_key(&wallet.address_at(wallet.next_index))
+        {
+            wallet.next_index += 1;
+        }
+        Ok(wallet)
+    }
+
+    /// Recreate a wallet from its mnemonic in a directory without a seed, deriving its first
+    /// `count` addresses.
+    pub fn restore(dir: &Path, passphrase: &str, phrase: &str, count: u32) -> io::Result<Self> {
+        let seed_path = dir.join(SEED_FILE);
+        if seed_path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", seed_path.display()),
+            ));
+        }
+        let mnemonic = hd::parse_mnemonic(phrase).map_err(invalid_data)?;
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&seed_path, encrypt(mnemonic.as_bytes(), passphrase))?;
+        let mut wallet = Self::open(dir, passphrase)?;
+        for _ in 0..count {
+            wallet.new_address()?;
+        }
+        Ok(wallet)
+    }
+
+    /// The seed phrase restoring the wallet
+    pub fn mnemonic(&self) -> &str {
+        &self.mnemonic
+    }
+
+    fn derive(&self, index: u32) -> ExtendedKey {
+        let path = [&ACCOUNT_PATH[..], &[index]].concat();
+        ExtendedKey::derive(&self.seed, &path)
+    }
+
+    fn address_at(&self, index: u32) -> H160 {
+        transaction::convertPubKeyToH160(self.derive(index).key_pair().public_key())
+    }
+
+    fn add(&mut self, key: Ed25519KeyPair) -> H160 {
+        let address = transaction::convertPubKeyToH160(key.public_key());
+        if self.keys.insert(address, key).is_none() {
+            self.addresses.push(address);
+        }
+        address
+    }
+
+    /// Derive the next key, save it and return its address.
+    pub fn new_address(&mut self) -> io::Result<H160> {
+        let derived = self.derive(self.next_index);
+        let key = derived.key_pair();
+        let address = transaction::convertPubKeyToH160(key.public_key());
+        let path = self.dir.join(format!("{}.key", hex::encode(address)));
+        std::fs::write(path, encrypt(&derived.pkcs8(), &self.passphrase))?;
+        self.next_index += 1;
+        Ok(self.add(key))
+    }
+
+    pub fn addresses(&self) -> &[H160] {
+        &self.addresses
+    }
+
+    /// The public key of one of the wallet addresses
+    pub fn pub_key(&self, address: &H160) -> Option<H256> {
+        self.keys
+            .get(address)
+            .map(|key| transaction::convertPubKeyToH256(key.public_key()))
+    }
+
+    /// The key of one of the wallet addresses, found by its public key
+    fn key_of(&self, pub_key: &H256) -> Option<(H160, &Ed25519KeyPair)> {
+        self.keys
+            .values()
+            .find(|key| transaction::convertPubKeyToH256(key.public_key()) == *pub_key)
+            .map(|key| (transaction::convertPubKeyToH160(key.public_key()), key))
+    }
+
+    /// The confirmed and pending balance of each address
+    fn balances(&self, blockchain: &Blockchain, mempool: &Mempool) -> HashMap<H160, Balance> {
+        let is_ours = |address: &H160| self.keys.contains_key(address);
+        let confirmed = balance_change(is_ours, confirmed_transactions(blockchain));
+        let pending = balance_change(is_ours, mempool.iter().map(|(_, x)| x));
+        self.addresses
+            .iter()
+            .map(|address| {
+                let balance = Balance {
+                    confirmed: confirmed.get(address).cloned().unwrap_or_default(),
+                    pending: pending.get(address).cloned().unwrap_or_default(),
+                };
+                (*address, balance)
+            })
+            .collect()
+    }
+
+    /// The total balance of the wallet addresses
+    pub fn balance(&self, blockchain: &Blockchain, mempool: &Mempool) -> Balance {
+        self.balances(blockchain, mempool)
+            .values()
+            .fold(Balance::default(), |total, x| Balance {
+                confirmed: total.confirmed + x.confirmed,
+                pending: total.pending + x.pending,
+            })
+    }
+
+    /// The address with the highest balance, counting pending transactions
+    fn richest(&self, blockchain: &Blockchain, mempool: &Mempool) -> Result<H160, String> {
+        let balances = self.balances(blockchain, mempool);
+        self.addresses
+            .iter()
+            .max_by(|a, b| {
+                let a = balances[a].confirmed + balances[a].pending;
+                let b = balances[b].confirmed + balances[b].pending;
+                a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .cloned()
+            .ok_or_else(|| "the wallet has no address".to_string())
+    }
+
+    /// Sign a transfer of `amount` to `to` from the address with the highest balance, counting
+    /// pending transactions. Fails if that balance does not cover the amount.
+    pub fn transfer(
+        &self,
+        to: H160,
+        amount: f32,
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+    ) -> Result<SignedTransaction, String> {
+        let from = self.richest(blockchain, mempool)?;
+        self.sign_transfer(from, to, amount, blockchain, mempool)
+    }
+
+    fn sign_transfer(
+        &self,
+        from: H160,
+        to: H160,
+        amount: f32,
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+    ) -> Result<SignedTransaction, String> {
+        let balance = self.balances(blockchain, mempool)[&from];
+        let available = balance.confirmed + balance.pending;
+        if amount > available {
+            return Err(format!(
+                "insufficient funds: {} available at {}",
+                available, from
+            ));
+        }
+        let key = &self.keys[&from];
+        let raw = Transaction {
+            input: from,
+            output: to,
+            amount,
+            lock_time: 0,
+            nonce: mempool.next_nonce(&from, blockchain.next_nonce(&from)),
+        };
+        let signature = transaction::sign(&raw, key);
+        let signed = SignedTransaction {
+            input: raw.input,
+            output: raw.output,
+            amount: raw.amount,
+            lock_time: raw.lock_time,
+            nonce: raw.nonce,
+            pub_key: transaction::convertPubKeyToH256(key.public_key()),
+            signature: transaction::convertSigToH256(&signature),
+            multisig: None,
+            script: None,
+        };
+        signed.validate(&Context::next_block(blockchain))?;
+        Ok(signed)
+    }
+
+    /// Lock `amount` in an HTLC paying `recipient` for the preimage of `hash`, refundable to the
+    /// address with the highest balance from `timeout`. Returns the contract and the transaction
+    /// funding it.
+    pub fn create_htlc(
+        &self,
+        recipient: H256,
+        hash: H256,
+        timeout: u64,
+        amount: f32,
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+    ) -> Result<(Htlc, SignedTransaction), String> {
+        let from = self.richest(blockchain, mempool)?;
+        let htlc = Htlc {
+            hash,
+            recipient,
+            sender: self.pub_key(&from).unwrap(),
+            timeout,
+        };
+        let funding = self.sign_transfer(from, htlc.address(), amount, blockchain, mempool)?;
+        Ok((htlc, funding))
+    }
+
+    /// Claim the confirmed funds of an HTLC paying one of the wallet keys, to the address of that
+    /// key.
+    pub fn claim_htlc(
+        &self,
+        htlc: &Htlc,
+        preimage: &[u8],
+        blockchain: &Blockchain,
+    ) -> Result<SignedTransaction, String> {
+        if htlc::hash_preimage(preimage) != htlc.hash {
+            return Err("the preimage does not match the hash".to_string());
+        }
+        let (to, key) = self
+            .key_of(&htlc.recipient)
+            .ok_or_else(|| "the wallet does not hold the recipient key".to_string())?;
+        let raw = htlc_spend(htlc, to, 0, blockchain)?;
+        let signed = htlc.claim(&raw, preimage, key);
+        signed.validate(&Context::next_block(blockchain))?;
+        Ok(signed)
+    }
+
+    /// Take back the confirmed funds of an HTLC sent from one of the wallet keys, to the address
+    /// of that key. The transaction is locked until the timeout, so it can be signed early and
+    /// broadcast once the timeout is reached.
+    pub fn refund_htlc(
+        &self,
+        htlc: &Htlc,
+        blockchain: &Blockchain,
+    ) -> Result<SignedTransaction, String> {
+        let (to, key) = self
+            .key_of(&htlc.sender)
+            .ok_or_else(|| "the wallet does not hold the sender key".to_string())?;
+        let raw = htlc_spend(htlc, to, htlc.timeout, blockchain)?;
+        Ok(htlc.refund(&raw, key))
+    }
+}
+
+/// A transaction spending the confirmed funds of an HTLC
+fn htlc_spend(
+    htlc: &Htlc,
+    to: H160,
+    lock_time: u64,
+    blockchain: &Blockchain,
+) -> Result<Transaction, String> {
+    let address = htlc.address();
+    let amount = balance_change(|x| *x == address, confirmed_transactions(blockchain))
+        .get(&address)
+        .cloned()
+        .unwrap_or_default();
+    if amount <= 0.0 {
+        return Err("the contract holds no confirmed funds".to_string());
+    }
+    Ok(Transaction {
+        input: address,
+        output: to,
+        amount,
+        lock_time,
+        nonce: blockchain.next_nonce(&address),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn keys_and_transfers() {
+        let dir = std::env::temp_dir().join(format!("wallet-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut wallet = Wallet::open(&dir, "correct horse").unwrap();
+        let first = wallet.new_address().unwrap();
+        let second = wallet.new_address().unwrap();
+        assert!(Wallet::open(&dir, "wrong").is_err());
+        let reopened = Wallet::open(&dir, "correct horse").unwrap();
+        let mut addresses = reopened.addresses().to_vec();
+        addresses.sort_by_key(|x| x.hash());
+        let mut expected = vec![first, second];
+        expected.sort_by_key(|x| x.hash());
+        assert_eq!(addresses, expected);
+
+        // first receives 5 in a block, then sends 2 from the mempool
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.data[0].output = first;
+        block.content.data[0].amount = 5.0;
+        blockchain.insert(&block);
+        let mut mempool = Mempool::new();
+        let to = H160::from([7; 20]);
+        let transfer = reopened.transfer(to, 2.0, &blockchain, &mempool).unwrap();
+        assert_eq!(transfer.input, first);
+        assert!(transfer.validate(&Context::next_block(&blockchain)).is_ok());
+        mempool.insert(transfer);
+        let balance = reopened.balance(&blockchain, &mempool);
+        assert_eq!(
+            balance,
+            Balance {
+                confirmed: 5.0,
+                pending: -2.0
+            }
+        );
+        // 3 are left to spend
+        assert!(reopened.transfer(to, 3.5, &blockchain, &mempool).is_err());
+        assert!(reopened.transfer(to, 3.0, &blockchain, &mempool).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_from_mnemonic() {
+        let dir = std::env::temp_dir().join(format!("wallet-restore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut wallet = Wallet::open(&dir.join("original"), "secret").unwrap();
+        let addresses: Vec<H160> = (0..3).map(|_| wallet.new_address().unwrap()).collect();
+
+        let restored =
+            Wallet::restore(&dir.join("restored"), "other", wallet.mnemonic(), 3).unwrap();
+        assert_eq!(restored.addresses(), &addresses[..]);
+        assert!(Wallet::restore(&dir.join("restored"), "other", wallet.mnemonic(), 3).is_err());
+        let mut reopened = Wallet::open(&dir.join("restored"), "other").unwrap();
+        let next = reopened.new_address().unwrap();
+        assert_eq!(next, wallet.new_address().unwrap());
+        assert!(Wallet::restore(&dir.join("bad"), "other", "not a mnemonic", 1).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}